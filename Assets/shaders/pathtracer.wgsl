@@ -27,6 +27,17 @@ struct OctreeVoxel {
     light_color: vec3<f32>,
     lit: u32,
     id: u32,
+    material: u32,
+}
+
+// kind: 0 diffuse, 1 metal, 2 glass, 3 emit, 4 blend, 5 media
+struct Material {
+    kind: u32,
+    roughness: f32,
+    metalness: f32,
+    ior: f32,
+    transparency: f32,
+    emission: f32,
 }
 
 struct ShaderScreen {
@@ -70,6 +81,7 @@ struct OctResult {
 @group(0) @binding(2) var<storage, read> screen: ShaderScreen;
 @group(0) @binding(3) var<storage, read> view_distance: u32;
 @group(0) @binding(4) var texture: texture_storage_2d<rgba8unorm, read_write>;
+@group(0) @binding(5) var<storage, read> materials: array<Material>;
 
 @compute @workgroup_size(16, 18, 1)
 fn update(@builtin(global_invocation_id) global_id: vec3<u32>) {
@@ -84,7 +96,8 @@ fn update(@builtin(global_invocation_id) global_id: vec3<u32>) {
     r.inv_direction = vec3<f32>(1.0/r.direction.x, 1.0/r.direction.y, 1.0/r.direction.z);
     var color = get_pixel_color(r);
 
-    textureStore(texture, position, color);
+    // the texture holds sRGB, it is sampled through an sRGB view
+    textureStore(texture, position, vec4<f32>(linear_to_srgb(color.rgb), color.a));
 }
 
 fn ray_dir_v4(x: f32, y: f32, fov: u32) -> vec3<f32> {
@@ -145,7 +158,7 @@ fn get_pixel_color(r: AabbRay) -> vec4<f32> {
         if node.voxel.id != 0 {
             if node.voxel.lit == 0 {
                 //indirect lighting
-                let photon = trunc(photon);
+                let photon = floor(photon);
                 var indir_light_color = vec3<f32>();
                 var dir_light_color = vec3<f32>();
                 let r1 = rand(vec2<f32>(photon.x, photon.y));
@@ -197,7 +210,8 @@ fn get_pixel_color(r: AabbRay) -> vec4<f32> {
                 leaves[last_index].voxel.light_color = indir_light_color + dir_light_color;
             }
             
-            let color = leaves[last_index].voxel.color * leaves[last_index].voxel.light_color;
+            let voxel = leaves[last_index].voxel;
+            let color = shade(voxel, materials[voxel.material]);
             return vec4<f32>(color[0], color[1], color[2], 1.0);
         }
 
@@ -212,6 +226,19 @@ fn get_pixel_color(r: AabbRay) -> vec4<f32> {
     return vec4<f32>(0.0, 0.0, 0.0, 1.0);
 }
 
+// lit colour of a voxel adjusted by its material: emissive voxels glow on top
+// of their lighting, smooth metal reflects the sky in its own tint and
+// transparent voxels let some of the sky through
+fn shade(voxel: OctreeVoxel, material: Material) -> vec3<f32> {
+    var color = voxel.color * voxel.light_color;
+    color += voxel.color * max(voxel.emission, material.emission);
+    if material.kind == 1u {
+        let shine = material.metalness * (1.0 - material.roughness);
+        color = mix(color, voxel.color * SKYCOLOR, shine);
+    }
+    return mix(color, SKYCOLOR, material.transparency * 0.5);
+}
+
 fn cast_ray(r: AabbRay, range: f32) -> RayResult {
     var length = 0.1;
     var steps = 0u;
@@ -239,7 +266,8 @@ fn cast_ray(r: AabbRay, range: f32) -> RayResult {
         }
 
         if node.voxel.id != 0 {
-            return RayResult(min(length, SKYDIST), node.voxel.emission, vec3<f32>(node.voxel.color[0], node.voxel.color[1], node.voxel.color[2]));
+            let emission = max(node.voxel.emission, materials[node.voxel.material].emission);
+            return RayResult(min(length, SKYDIST), emission, vec3<f32>(node.voxel.color[0], node.voxel.color[1], node.voxel.color[2]));
         }
 
         //continue to next safe dist
@@ -353,6 +381,13 @@ fn rand(co: vec2<f32>) -> f32 {
     return fract(sin(dot(co, vec2(12.9898, 78.233))) * 43758.5453);
 }
 
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+    let c = clamp(c, vec3<f32>(0.0), vec3<f32>(1.0));
+    let low = c * 12.92;
+    let high = 1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, c <= vec3<f32>(0.0031308));
+}
+
 fn map_range(a: f32, b: f32, c: f32, d: f32, s: f32) -> f32 {
     return (c + (s - a) * (d - c) / (b - a));
 }
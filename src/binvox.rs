@@ -0,0 +1,152 @@
+use std::{fs, io, path::Path};
+
+use bevy::prelude::*;
+
+use crate::{
+    region::invalid_data,
+    world_generator::{color_voxel, insert_voxel_list, StorageVoxel, VoxelEntity, WorldData},
+};
+
+/// Solid cells of a `.binvox` file. The format has no colours, every voxel
+/// gets the colour it is imported with. The placement of the voxelized mesh
+/// in its original units is not kept, voxels are placed on the world grid.
+#[derive(Clone)]
+pub struct Binvox {
+    pub voxels: Vec<[i32; 3]>,
+}
+impl Binvox {
+    pub fn colored(self: &Self, color: dot_vox::Color) -> Vec<([i32; 3], StorageVoxel)> {
+        let vox = color_voxel(color, 0.0);
+        self.voxels.iter().map(|pos| (*pos, vox.clone())).collect()
+    }
+
+    pub fn to_entity(
+        self: &Self,
+        name: &str,
+        color: dot_vox::Color,
+        offset: [i32; 3],
+    ) -> VoxelEntity {
+        VoxelEntity {
+            name: name.to_string(),
+            transform: Transform::from_xyz(offset[0] as f32, offset[1] as f32, offset[2] as f32),
+            voxels: self.colored(color),
+        }
+    }
+}
+
+/// Reads a `.binvox` file: a text header followed by run length encoded
+/// (value, count) byte pairs, with y running fastest, then z, then x.
+pub fn load_binvox(path: &Path) -> io::Result<Binvox> {
+    let bytes = fs::read(path)?;
+
+    let mut at = 0;
+    let mut next_line = || -> io::Result<String> {
+        let end = bytes[at..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid_data("binvox header ends early"))?;
+        let line = String::from_utf8_lossy(&bytes[at..at + end])
+            .trim()
+            .to_string();
+        at += end + 1;
+        Ok(line)
+    };
+
+    if !next_line()?.starts_with("#binvox") {
+        return Err(invalid_data("not a binvox file"));
+    }
+    let mut dims = None;
+    loop {
+        let line = next_line()?;
+        let mut parts = line.split_whitespace();
+        let numbers: Vec<f32> = parts
+            .clone()
+            .skip(1)
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        match parts.next() {
+            Some("dim") if numbers.len() == 3 => {
+                dims = Some([numbers[0] as u32, numbers[1] as u32, numbers[2] as u32])
+            }
+            Some("translate") if numbers.len() == 3 => {}
+            Some("scale") if numbers.len() == 1 => {}
+            Some("data") => break,
+            _ => return Err(invalid_data(&format!("unexpected header line: {}", line))),
+        }
+    }
+    let dims = dims.ok_or_else(|| invalid_data("binvox file has no dim line"))?;
+    // depth, width, height
+    let [depth, width, height] = dims;
+    let total = depth as usize * width as usize * height as usize;
+
+    let mut voxels = Vec::new();
+    let mut index = 0;
+    for pair in bytes[at..].chunks_exact(2) {
+        let (value, count) = (pair[0], pair[1] as usize);
+        if index + count > total {
+            return Err(invalid_data("binvox data runs past the grid"));
+        }
+        if value != 0 {
+            for i in index..index + count {
+                let x = i / (width as usize * height as usize);
+                let z = (i / height as usize) % width as usize;
+                let y = i % height as usize;
+                voxels.push([x as i32, y as i32, z as i32]);
+            }
+        }
+        index += count;
+    }
+
+    Ok(Binvox { voxels })
+}
+
+/// Puts a `.binvox` file into the world at `offset` in a single colour.
+pub fn insert_binvox(
+    binvox: &Binvox,
+    color: dot_vox::Color,
+    offset: [i32; 3],
+    world: &mut WorldData,
+) {
+    insert_voxel_list(&binvox.colored(color), offset, world);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> Binvox {
+        load_binvox(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/model.binvox"))
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_runs_with_y_fastest_then_z_then_x() {
+        let mut voxels = fixture().voxels;
+        voxels.sort();
+        assert_eq!(voxels, vec![[0, 0, 0], [0, 2, 1], [1, 1, 0]]);
+    }
+
+    #[test]
+    fn inserts_in_one_colour() {
+        let binvox = fixture();
+        let color = dot_vox::Color {
+            r: 10,
+            g: 20,
+            b: 30,
+            a: 255,
+        };
+        let mut world = WorldData::default();
+        insert_binvox(&binvox, color, [-8, 4, 0], &mut world);
+        for pos in [[-8, 4, 0], [-8, 6, 1], [-7, 5, 0]] {
+            assert_eq!(
+                world.data.get_voxel(pos).map(|v| v.color),
+                Some([10, 20, 30])
+            );
+        }
+
+        let entity = binvox.to_entity("statue", color, [-8, 4, 0]);
+        assert_eq!(entity.name, "statue");
+        assert_eq!(entity.transform.translation, Vec3::new(-8.0, 4.0, 0.0));
+        assert_eq!(entity.voxels.len(), 3);
+    }
+}
@@ -0,0 +1,384 @@
+use bevy::utils::HashMap;
+
+use crate::world_generator::{StorageVoxel, C_SIZE};
+
+pub const CHUNK_VOLUME: usize = (C_SIZE * C_SIZE * C_SIZE) as usize;
+/// A sparse chunk is converted to dense once it holds more voxels than this.
+pub const DENSE_THRESHOLD: usize = CHUNK_VOLUME / 32;
+/// A dense chunk goes back to sparse below this, the gap between the two
+/// thresholds keeps a chunk from flipping back and forth on every edit.
+pub const SPARSE_THRESHOLD: usize = CHUNK_VOLUME / 64;
+
+/// Voxels of one chunk, addressed by their position local to the chunk.
+#[derive(Clone)]
+pub struct Chunk {
+    storage: ChunkStorage,
+}
+
+#[derive(Clone)]
+enum ChunkStorage {
+    Sparse(HashMap<[u8; 3], StorageVoxel>),
+    Dense(DenseChunk),
+}
+
+/// A `C_SIZE`³ array of palette indices, bit packed into `u64` words. Index 0
+/// is empty space, index `n` refers to `palette[n - 1]`. Indices never cross a
+/// word boundary.
+#[derive(Clone)]
+struct DenseChunk {
+    palette: Vec<StorageVoxel>,
+    /// Palette index of every entry in `palette`.
+    lookup: HashMap<VoxelKey, u32>,
+    /// Number of voxels using each palette entry, so unused ones can be reused.
+    refs: Vec<u32>,
+    /// Entries whose count dropped to zero, checked again before reuse.
+    free: Vec<u32>,
+    bits: u32,
+    words: Vec<u64>,
+    count: usize,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk {
+            storage: ChunkStorage::Sparse(HashMap::new()),
+        }
+    }
+}
+
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(pos, vox)| other.get(pos) == Some(vox))
+    }
+}
+
+impl Chunk {
+    pub fn get(self: &Self, local: [u8; 3]) -> Option<&StorageVoxel> {
+        match &self.storage {
+            ChunkStorage::Sparse(voxels) => voxels.get(&local),
+            ChunkStorage::Dense(dense) => dense.get(flat_index(local)),
+        }
+    }
+
+    /// Places a voxel, returning the one it replaced.
+    pub fn set(self: &mut Self, local: [u8; 3], voxel: StorageVoxel) -> Option<StorageVoxel> {
+        let old = match &mut self.storage {
+            ChunkStorage::Sparse(voxels) => voxels.insert(local, voxel),
+            ChunkStorage::Dense(dense) => dense.set(flat_index(local), Some(voxel)),
+        };
+        self.convert();
+        old
+    }
+
+    pub fn remove(self: &mut Self, local: [u8; 3]) -> Option<StorageVoxel> {
+        let old = match &mut self.storage {
+            ChunkStorage::Sparse(voxels) => voxels.remove(&local),
+            ChunkStorage::Dense(dense) => dense.set(flat_index(local), None),
+        };
+        self.convert();
+        old
+    }
+
+    pub fn len(self: &Self) -> usize {
+        match &self.storage {
+            ChunkStorage::Sparse(voxels) => voxels.len(),
+            ChunkStorage::Dense(dense) => dense.count,
+        }
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the voxels in the chunk with their local position. Dense
+    /// chunks are visited in x, y, z order, sparse ones in no particular order.
+    pub fn iter(self: &Self) -> Box<dyn Iterator<Item = ([u8; 3], &StorageVoxel)> + '_> {
+        match &self.storage {
+            ChunkStorage::Sparse(voxels) => Box::new(voxels.iter().map(|(pos, vox)| (*pos, vox))),
+            ChunkStorage::Dense(dense) => Box::new(
+                (0..CHUNK_VOLUME).filter_map(|i| dense.get(i).map(|vox| (local_position(i), vox))),
+            ),
+        }
+    }
+
+    /// Palette and run length encoded indices in x, y, z order. The palette is
+    /// built in order of first appearance so the result does not depend on how
+    /// the chunk happens to be stored. Indices are `u32`, a chunk can hold
+    /// more distinct voxels than fit in a `u16`.
+    pub fn to_runs(self: &Self) -> (Vec<StorageVoxel>, Vec<(u32, u32)>) {
+        let mut palette: Vec<StorageVoxel> = Vec::new();
+        let mut lookup: HashMap<VoxelKey, u32> = HashMap::new();
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for i in 0..CHUNK_VOLUME {
+            let index = match self.get(local_position(i)) {
+                Some(vox) => *lookup.entry(voxel_key(vox)).or_insert_with(|| {
+                    palette.push(vox.clone());
+                    palette.len() as u32
+                }),
+                None => 0,
+            };
+            match runs.last_mut() {
+                Some((length, last)) if *last == index => *length += 1,
+                _ => runs.push((1, index)),
+            }
+        }
+        (palette, runs)
+    }
+
+    pub fn from_runs(palette: &[StorageVoxel], runs: &[(u32, u32)]) -> Option<Self> {
+        let mut chunk = Chunk::default();
+        let mut i = 0;
+        for (length, index) in runs.iter() {
+            if i + *length as usize > CHUNK_VOLUME {
+                return None;
+            }
+            if *index != 0 {
+                let vox = palette.get(*index as usize - 1)?;
+                for n in i..i + *length as usize {
+                    chunk.set(local_position(n), vox.clone());
+                }
+            }
+            i += *length as usize;
+        }
+        Some(chunk)
+    }
+
+    /// Switches between sparse and dense storage based on how full the chunk is.
+    fn convert(self: &mut Self) {
+        match &self.storage {
+            ChunkStorage::Sparse(voxels) if voxels.len() > DENSE_THRESHOLD => {
+                let mut dense = DenseChunk::new();
+                for (pos, vox) in voxels.iter() {
+                    dense.set(flat_index(*pos), Some(vox.clone()));
+                }
+                self.storage = ChunkStorage::Dense(dense);
+            }
+            ChunkStorage::Dense(dense) if dense.count < SPARSE_THRESHOLD => {
+                let mut voxels = HashMap::with_capacity(dense.count);
+                for i in 0..CHUNK_VOLUME {
+                    if let Some(vox) = dense.get(i) {
+                        voxels.insert(local_position(i), vox.clone());
+                    }
+                }
+                self.storage = ChunkStorage::Sparse(voxels);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DenseChunk {
+    fn new() -> Self {
+        DenseChunk {
+            palette: Vec::new(),
+            lookup: HashMap::new(),
+            refs: Vec::new(),
+            free: Vec::new(),
+            bits: 1,
+            words: vec![0; words_for(1)],
+            count: 0,
+        }
+    }
+
+    fn index(self: &Self, i: usize) -> u32 {
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        ((self.words[i / per_word] >> shift) & ((1 << self.bits) - 1)) as u32
+    }
+
+    fn set_index(self: &mut Self, i: usize, index: u32) {
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.words[i / per_word];
+        *word = (*word & !mask) | ((index as u64) << shift);
+    }
+
+    fn get(self: &Self, i: usize) -> Option<&StorageVoxel> {
+        match self.index(i) {
+            0 => None,
+            index => Some(&self.palette[index as usize - 1]),
+        }
+    }
+
+    fn set(self: &mut Self, i: usize, voxel: Option<StorageVoxel>) -> Option<StorageVoxel> {
+        let old_index = self.index(i);
+        let old = match old_index {
+            0 => None,
+            index => Some(self.palette[index as usize - 1].clone()),
+        };
+
+        let new_index = match voxel {
+            Some(voxel) => self.palette_index(voxel),
+            None => 0,
+        };
+        if new_index != 0 {
+            self.refs[new_index as usize - 1] += 1;
+        }
+        if old_index != 0 {
+            self.refs[old_index as usize - 1] -= 1;
+            if self.refs[old_index as usize - 1] == 0 {
+                self.free.push(old_index);
+            }
+        }
+
+        match (old_index, new_index) {
+            (0, n) if n != 0 => self.count += 1,
+            (o, 0) if o != 0 => self.count -= 1,
+            _ => {}
+        }
+
+        self.set_index(i, new_index);
+        old
+    }
+
+    /// Finds or adds the palette entry for `voxel`, widening the indices when
+    /// the palette outgrows them.
+    fn palette_index(self: &mut Self, voxel: StorageVoxel) -> u32 {
+        let key = voxel_key(&voxel);
+        if let Some(index) = self.lookup.get(&key) {
+            return *index;
+        }
+        while let Some(index) = self.free.pop() {
+            // the entry may have been used again since it was freed
+            if self.refs[index as usize - 1] != 0 {
+                continue;
+            }
+            let old = std::mem::replace(&mut self.palette[index as usize - 1], voxel);
+            self.lookup.remove(&voxel_key(&old));
+            self.lookup.insert(key, index);
+            return index;
+        }
+
+        self.palette.push(voxel);
+        self.refs.push(0);
+        let index = self.palette.len() as u32;
+        self.lookup.insert(key, index);
+        if index as u64 > (1u64 << self.bits) - 1 {
+            self.repack(self.bits + 1);
+        }
+        index
+    }
+
+    fn repack(self: &mut Self, bits: u32) {
+        let indices: Vec<u32> = (0..CHUNK_VOLUME).map(|i| self.index(i)).collect();
+        self.bits = bits;
+        self.words = vec![0; words_for(bits)];
+        for (i, index) in indices.into_iter().enumerate() {
+            if index != 0 {
+                self.set_index(i, index);
+            }
+        }
+    }
+}
+
+/// Voxels compared by their bits, so they can be hashed.
+type VoxelKey = (u8, [u8; 3], u32, u16);
+
+fn voxel_key(vox: &StorageVoxel) -> VoxelKey {
+    (vox.id, vox.color, vox.emission.to_bits(), vox.material)
+}
+
+fn words_for(bits: u32) -> usize {
+    let per_word = 64 / bits as usize;
+    (CHUNK_VOLUME + per_word - 1) / per_word
+}
+
+fn flat_index(local: [u8; 3]) -> usize {
+    let size = C_SIZE as usize;
+    (local[0] as usize * size + local[1] as usize) * size + local[2] as usize
+}
+
+fn local_position(i: usize) -> [u8; 3] {
+    let size = C_SIZE as usize;
+    [
+        (i / (size * size)) as u8,
+        ((i / size) % size) as u8,
+        (i % size) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(n: u32) -> StorageVoxel {
+        StorageVoxel {
+            id: 1,
+            color: [n as u8, (n >> 8) as u8, (n >> 16) as u8],
+            emission: 0.0,
+            material: 0,
+        }
+    }
+
+    fn is_dense(chunk: &Chunk) -> bool {
+        matches!(chunk.storage, ChunkStorage::Dense(_))
+    }
+
+    #[test]
+    fn switches_storage_with_hysteresis() {
+        let mut chunk = Chunk::default();
+        for i in 0..=DENSE_THRESHOLD {
+            chunk.set(local_position(i), voxel(i as u32 % 7));
+            assert_eq!(is_dense(&chunk), i == DENSE_THRESHOLD);
+        }
+
+        // stays dense until it drops below the lower threshold
+        for i in (SPARSE_THRESHOLD - 1..=DENSE_THRESHOLD).rev() {
+            chunk.remove(local_position(i));
+            assert_eq!(is_dense(&chunk), chunk.len() >= SPARSE_THRESHOLD);
+        }
+        assert!(!is_dense(&chunk));
+        assert_eq!(chunk.len(), SPARSE_THRESHOLD - 1);
+        for i in 0..SPARSE_THRESHOLD - 1 {
+            assert_eq!(chunk.get(local_position(i)), Some(&voxel(i as u32 % 7)));
+        }
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let mut chunk = Chunk::default();
+        for i in (0..CHUNK_VOLUME).step_by(3) {
+            chunk.set(local_position(i), voxel((i / 1000) as u32));
+        }
+        assert!(is_dense(&chunk));
+
+        let (palette, runs) = chunk.to_runs();
+        let decoded = Chunk::from_runs(&palette, &runs).unwrap();
+        assert!(decoded == chunk);
+        // the encoding only depends on the voxels
+        assert_eq!(decoded.to_runs().1, runs);
+    }
+
+    #[test]
+    fn runs_keep_more_than_u16_palette_entries() {
+        let mut chunk = Chunk::default();
+        let distinct = u16::MAX as usize + 100;
+        for i in 0..distinct {
+            chunk.set(local_position(i), voxel(i as u32));
+        }
+
+        let (palette, runs) = chunk.to_runs();
+        assert_eq!(palette.len(), distinct);
+        assert!(Chunk::from_runs(&palette, &runs).unwrap() == chunk);
+    }
+
+    #[test]
+    fn freed_palette_entries_are_reused() {
+        let mut chunk = Chunk::default();
+        for i in 0..=DENSE_THRESHOLD {
+            chunk.set(local_position(i), voxel(1));
+        }
+        chunk.set(local_position(0), voxel(2));
+        // frees the entry of voxel 2, which voxel 4 then takes over
+        chunk.set(local_position(0), voxel(3));
+        chunk.set(local_position(1), voxel(4));
+        match &chunk.storage {
+            ChunkStorage::Dense(dense) => assert_eq!(dense.palette.len(), 3),
+            ChunkStorage::Sparse(_) => panic!("chunk should be dense"),
+        }
+        assert_eq!(chunk.get(local_position(0)), Some(&voxel(3)));
+        assert_eq!(chunk.get(local_position(1)), Some(&voxel(4)));
+        assert_eq!(chunk.get(local_position(2)), Some(&voxel(1)));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    material::{MaterialTable, ShaderMaterial, MAX_MATERIALS},
     octree::{Octree, ShaderOctree},
     pre_compute::{RESHIGHT, RESWIDTH},
     world_generator::VIEWDIST,
@@ -46,6 +47,7 @@ pub struct RayTracerBuffers {
     leaves: Buffer,
     screen: Buffer,
     view_distance: Buffer,
+    materials: Buffer,
 }
 
 #[derive(Resource)]
@@ -60,6 +62,13 @@ pub struct LeafBufferData(pub Arc<Mutex<Vec<u8>>>);
 #[derive(Resource, Default)]
 struct SerialiseTrigger(Arc<Mutex<bool>>);
 
+/// Copy of the `MaterialTable`, taken whenever it has grown.
+#[derive(Resource, Default)]
+struct ExtractedMaterials {
+    materials: Vec<ShaderMaterial>,
+    changed: bool,
+}
+
 #[derive(Resource, Default, Clone, Copy, ShaderType)]
 pub struct ShaderScreen {
     pub pos: Vec3,
@@ -90,6 +99,7 @@ impl Plugin for RayTracerPlugin {
             .init_resource::<LeafBufferData>()
             .init_resource::<SerialiseTrigger>()
             .init_resource::<ShaderScreen>()
+            .init_resource::<ExtractedMaterials>()
             .add_event::<UpdatesOctreeBuffer>()
             .add_systems(ExtractSchedule, extract_resources)
             .add_systems(
@@ -117,6 +127,7 @@ impl Plugin for RayTracerPlugin {
                 leaves: setup_leaves_buffer(render_device.clone()),
                 screen: setup_screen_buffer(render_device.clone()),
                 view_distance: setup_view_distance_buffer(render_device.clone()),
+                materials: setup_materials_buffer(render_device.clone()),
             });
     }
 }
@@ -125,6 +136,7 @@ fn extract_resources(
     world: ResMut<MainWorld>,
     mut octree: ResMut<ComputeOctree>,
     mut screen: ResMut<ShaderScreen>,
+    mut materials: ResMut<ExtractedMaterials>,
     mut event_writer: EventWriter<UpdatesOctreeBuffer>,
 ) {
     let now = Instant::now();
@@ -146,6 +158,14 @@ fn extract_resources(
     screen.width = o_screen.width;
     screen.fov = o_screen.fov;
 
+    // the table only grows, so a new count means new materials
+    if let Some(table) = world.get_resource::<MaterialTable>() {
+        if table.count() != materials.materials.len() {
+            materials.materials = table.to_shader();
+            materials.changed = true;
+        }
+    }
+
     let elapsed = now.elapsed().as_millis();
     if elapsed > 2 {
         info!("extracting resources took: {}", elapsed)
@@ -157,6 +177,7 @@ fn update_buffers(
     octree: Res<ComputeOctree>,
     leaf_data: Res<LeafBufferData>,
     screen: Res<ShaderScreen>,
+    mut materials: ResMut<ExtractedMaterials>,
     render_queue: Res<RenderQueue>,
     trigger: Res<SerialiseTrigger>,
     mut event_reader: EventReader<UpdatesOctreeBuffer>,
@@ -199,6 +220,14 @@ fn update_buffers(
     }
 
     update_screen_buffer(render_queue.clone(), &raytracer_buffer.screen, *screen);
+    if materials.changed {
+        update_materials_buffer(
+            render_queue.clone(),
+            &raytracer_buffer.materials,
+            &materials.materials,
+        );
+        materials.changed = false;
+    }
 
     let elapsed = now.elapsed().as_millis();
     if elapsed > 20 {
@@ -220,6 +249,11 @@ fn prepare_bind_group(
         .unwrap();
     match gpu_view.texture_format {
         wgpu::TextureFormat::Rgba8Unorm => {
+            // the image's own view is sRGB, which can't be a storage binding
+            let storage_view = gpu_view.texture.create_view(&wgpu::TextureViewDescriptor {
+                format: Some(wgpu::TextureFormat::Rgba8Unorm),
+                ..Default::default()
+            });
             let bind_group = render_device.create_bind_group(
                 None,
                 &pipeline.texture_bind_group_layout,
@@ -228,7 +262,8 @@ fn prepare_bind_group(
                     (1, raytracer_buffer.leaves.as_entire_buffer_binding()),
                     (2, raytracer_buffer.screen.as_entire_buffer_binding()),
                     (3, raytracer_buffer.view_distance.as_entire_buffer_binding()),
-                    (4, BindingResource::TextureView(&gpu_view.texture_view)),
+                    (4, BindingResource::TextureView(&storage_view)),
+                    (5, raytracer_buffer.materials.as_entire_buffer_binding()),
                 )),
             );
             commands.insert_resource(RayTracerBufferBindGroup(bind_group));
@@ -306,6 +341,16 @@ impl FromWorld for RayTracePipeLine {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         );
         let shader = world
@@ -502,3 +547,29 @@ fn setup_view_distance_buffer(render_device: RenderDevice) -> Buffer {
         usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
     })
 }
+
+fn setup_materials_buffer(render_device: RenderDevice) -> Buffer {
+    let mut byte_buffer = Vec::new();
+    let mut buffer = StorageBuffer::new(&mut byte_buffer);
+    buffer
+        .write(&vec![ShaderMaterial::default(); MAX_MATERIALS])
+        .unwrap();
+    render_device.create_buffer_with_data(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: buffer.into_inner(),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+fn update_materials_buffer(
+    render_queue: RenderQueue,
+    buffer: &Buffer,
+    materials: &[ShaderMaterial],
+) {
+    let mut byte_buffer = Vec::new();
+    let mut temp_buffer = StorageBuffer::new(&mut byte_buffer);
+    temp_buffer
+        .write(&materials[..materials.len().min(MAX_MATERIALS)].to_vec())
+        .unwrap();
+    render_queue.write_buffer(buffer, 0, temp_buffer.into_inner());
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::world_generator::{StorageVoxel, VoxWorld, VoxelChange};
+
+/// Every voxel change made by one undoable step, in the order they happened.
+#[derive(Clone, Default)]
+pub struct EditOperation {
+    pub changes: Vec<VoxelChange>,
+}
+impl EditOperation {
+    /// Folds repeated edits of the same voxel into one change that goes from
+    /// the first `before` to the last `after`, dropping ones that cancel out.
+    fn compact(self: &mut Self) {
+        let mut index: HashMap<[i32; 3], usize> = HashMap::new();
+        let mut compacted: Vec<VoxelChange> = Vec::new();
+        for change in self.changes.drain(..) {
+            match index.get(&change.pos) {
+                Some(i) => compacted[*i].after = change.after,
+                None => {
+                    index.insert(change.pos, compacted.len());
+                    compacted.push(change);
+                }
+            }
+        }
+        compacted.retain(|change| change.before != change.after);
+        self.changes = compacted;
+    }
+}
+
+/// Undo and redo stacks for world edits. Edits go through the methods here
+/// instead of straight to `VoxWorld` to be recorded. Everything between
+/// `begin_group` and `end_group`, like all the dabs of one brush stroke, is
+/// undone as a single step.
+#[derive(Resource)]
+pub struct EditHistory {
+    undo: VecDeque<EditOperation>,
+    redo: Vec<EditOperation>,
+    group: Option<EditOperation>,
+    depth: u32,
+    /// Upper bound on recorded voxel changes over both stacks, the oldest
+    /// operations are forgotten first. Each change is roughly 50 bytes.
+    pub max_changes: usize,
+    recorded: usize,
+}
+impl Default for EditHistory {
+    fn default() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            group: None,
+            depth: 0,
+            max_changes: 1_000_000,
+            recorded: 0,
+        }
+    }
+}
+
+impl EditHistory {
+    pub fn begin_group(self: &mut Self) {
+        if self.depth == 0 {
+            self.group = Some(EditOperation::default());
+        }
+        self.depth += 1;
+    }
+
+    pub fn end_group(self: &mut Self) {
+        if self.depth == 0 {
+            warn!("end_group called without a matching begin_group");
+            return;
+        }
+        self.depth -= 1;
+        if self.depth == 0 {
+            if let Some(mut op) = self.group.take() {
+                op.compact();
+                self.push(op);
+            }
+        }
+    }
+
+    /// Records changes that were already applied to the world.
+    pub fn record(self: &mut Self, changes: Vec<VoxelChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        match &mut self.group {
+            Some(group) => group.changes.extend(changes),
+            None => self.push(EditOperation { changes }),
+        }
+    }
+
+    pub fn set_voxel(self: &mut Self, world: &VoxWorld, pos: [i32; 3], voxel: StorageVoxel) {
+        self.record(world.apply(vec![(pos, Some(voxel))]));
+    }
+
+    pub fn clear_voxel(self: &mut Self, world: &VoxWorld, pos: [i32; 3]) {
+        self.record(world.apply(vec![(pos, None)]));
+    }
+
+    pub fn edit_region(
+        self: &mut Self,
+        world: &VoxWorld,
+        min: [i32; 3],
+        max: [i32; 3],
+        f: impl FnMut([i32; 3], Option<&StorageVoxel>) -> Option<StorageVoxel>,
+    ) {
+        self.record(world.edit_region(min, max, f));
+    }
+
+    /// Also true while a group with changes is still open, `undo` closes it.
+    pub fn can_undo(self: &Self) -> bool {
+        !self.undo.is_empty()
+            || self
+                .group
+                .as_ref()
+                .map_or(false, |group| !group.changes.is_empty())
+    }
+
+    pub fn can_redo(self: &Self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Reverts the last operation, returns false if there was nothing to undo.
+    pub fn undo(self: &mut Self, world: &VoxWorld) -> bool {
+        self.close_group();
+        let op = match self.undo.pop_back() {
+            Some(op) => op,
+            None => return false,
+        };
+        world.apply(
+            op.changes
+                .iter()
+                .rev()
+                .map(|change| (change.pos, change.before.clone()))
+                .collect(),
+        );
+        self.redo.push(op);
+        true
+    }
+
+    pub fn redo(self: &mut Self, world: &VoxWorld) -> bool {
+        self.close_group();
+        let op = match self.redo.pop() {
+            Some(op) => op,
+            None => return false,
+        };
+        world.apply(
+            op.changes
+                .iter()
+                .map(|change| (change.pos, change.after.clone()))
+                .collect(),
+        );
+        self.undo.push_back(op);
+        true
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.recorded = 0;
+    }
+
+    fn push(self: &mut Self, op: EditOperation) {
+        if op.changes.is_empty() {
+            return;
+        }
+
+        self.recorded -= self
+            .redo
+            .drain(..)
+            .map(|op| op.changes.len())
+            .sum::<usize>();
+
+        // an operation that does not fit on its own cannot be undone, and the
+        // older ones would no longer apply cleanly on top of it
+        if op.changes.len() > self.max_changes {
+            warn!(
+                "edit of {} voxels is too large to undo, clearing history",
+                op.changes.len()
+            );
+            self.clear();
+            return;
+        }
+
+        self.recorded += op.changes.len();
+        self.undo.push_back(op);
+        while self.recorded > self.max_changes {
+            match self.undo.pop_front() {
+                Some(old) => self.recorded -= old.changes.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Undoing in the middle of a stroke finishes the stroke first.
+    fn close_group(self: &mut Self) {
+        if self.depth > 0 {
+            self.depth = 1;
+            self.end_group();
+        }
+    }
+}
+
+pub fn undo_redo_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    world: Res<VoxWorld>,
+    mut history: ResMut<EditHistory>,
+) {
+    if !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+
+    if keys.just_pressed(KeyCode::KeyZ) && !shift {
+        if !history.can_undo() {
+            info!("Nothing to undo");
+            return;
+        }
+        history.undo(&world);
+    } else if keys.just_pressed(KeyCode::KeyY) || (keys.just_pressed(KeyCode::KeyZ) && shift) {
+        if !history.can_redo() {
+            info!("Nothing to redo");
+            return;
+        }
+        history.redo(&world);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(id: u8) -> StorageVoxel {
+        StorageVoxel {
+            id,
+            color: [id, id, id],
+            emission: 0.0,
+            material: 0,
+        }
+    }
+
+    fn snapshot(world: &VoxWorld) -> Vec<([i32; 3], StorageVoxel)> {
+        let mut voxels = world.voxels_in([-100; 3], [100; 3]);
+        voxels.sort_by_key(|(pos, _)| *pos);
+        voxels
+    }
+
+    #[test]
+    fn undo_then_redo_restores_the_world() {
+        let world = VoxWorld::default();
+        let mut history = EditHistory::default();
+        world.set_voxel([5, 5, 5], voxel(9));
+
+        history.begin_group();
+        history.set_voxel(&world, [0, 0, 0], voxel(1));
+        history.set_voxel(&world, [0, 0, 0], voxel(2));
+        history.clear_voxel(&world, [5, 5, 5]);
+        history.edit_region(&world, [-1, 70, -1], [1, 70, 1], |_, _| Some(voxel(3)));
+        assert!(history.can_undo());
+        history.end_group();
+
+        let before = snapshot(&world);
+        assert_eq!(before.len(), 10);
+        assert!(!history.can_redo());
+
+        assert!(history.undo(&world));
+        assert_eq!(snapshot(&world), vec![([5, 5, 5], voxel(9))]);
+        assert!(!history.can_undo());
+        assert!(history.can_redo());
+
+        assert!(history.redo(&world));
+        assert_eq!(snapshot(&world), before);
+        assert!(!history.redo(&world));
+    }
+
+    #[test]
+    fn oldest_operations_are_forgotten_past_max_changes() {
+        let world = VoxWorld::default();
+        let mut history = EditHistory {
+            max_changes: 3,
+            ..Default::default()
+        };
+        for x in 0..4 {
+            history.set_voxel(&world, [x, 0, 0], voxel(1));
+        }
+
+        for _ in 0..3 {
+            assert!(history.undo(&world));
+        }
+        assert!(!history.can_undo());
+        // the first edit can no longer be undone and stays
+        assert_eq!(snapshot(&world), vec![([0, 0, 0], voxel(1))]);
+    }
+
+    #[test]
+    fn edits_larger_than_max_changes_clear_the_history() {
+        let world = VoxWorld::default();
+        let mut history = EditHistory {
+            max_changes: 3,
+            ..Default::default()
+        };
+        history.set_voxel(&world, [0, 0, 0], voxel(1));
+        history.edit_region(&world, [0, 1, 0], [3, 1, 0], |_, _| Some(voxel(2)));
+        assert!(!history.can_undo());
+        assert!(!history.undo(&world));
+        assert_eq!(snapshot(&world).len(), 5);
+    }
+
+    #[test]
+    fn new_edits_drop_the_redo_stack() {
+        let world = VoxWorld::default();
+        let mut history = EditHistory::default();
+        history.set_voxel(&world, [0, 0, 0], voxel(1));
+        history.undo(&world);
+        assert!(history.can_redo());
+        history.set_voxel(&world, [1, 0, 0], voxel(1));
+        assert!(!history.can_redo());
+    }
+}
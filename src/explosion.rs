@@ -0,0 +1,302 @@
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin};
+
+use crate::{
+    edit_history::EditHistory,
+    fluid::fluid_of,
+    particles::{ParticleEmitter, ParticleSettings, Particles},
+    player_controller::PCamera,
+    rigid_body::RigidBody,
+    streaming::FloatingOrigin,
+    world_generator::{StorageVoxel, VoxWorld, VoxelChange, VoxelEntity},
+};
+
+/// Colour debris glows with as it flies off, blended with its own.
+const EMBER_COLOR: [u8; 3] = [255, 110, 30];
+
+#[derive(Resource, Clone)]
+pub struct ExplosionSettings {
+    /// Spawn some of the removed voxels as glowing debris.
+    pub debris: bool,
+    pub max_debris: usize,
+    /// Seconds until debris has faded out and is removed.
+    pub debris_lifetime: f32,
+    /// How far the edge of the crater wobbles, relative to the radius.
+    pub roughness: f32,
+    /// Voxels this far beyond the crater, relative to the radius, are
+    /// darkened, most at the edge of the crater.
+    pub char_width: f32,
+    /// Radius and strength of explosions set off with `X`.
+    pub radius: f32,
+    pub strength: f32,
+}
+impl Default for ExplosionSettings {
+    fn default() -> Self {
+        Self {
+            debris: true,
+            max_debris: 48,
+            debris_lifetime: 2.5,
+            roughness: 0.3,
+            char_width: 0.5,
+            radius: 6.0,
+            strength: 40.0,
+        }
+    }
+}
+
+/// An explosion at a world position. `strength` is the speed, in voxels per
+/// second, that debris and nearby bodies are thrown with. Set off with
+/// `explode`.
+#[derive(Event, Clone, Copy)]
+pub struct Explosion {
+    pub center: [i32; 3],
+    pub radius: f32,
+    pub strength: f32,
+}
+
+/// Debris that loses its glow over its lifetime and is then despawned.
+#[derive(Component)]
+pub struct Fading {
+    pub age: f32,
+    pub lifetime: f32,
+    /// Emission of each voxel when it was spawned.
+    pub emission: Vec<f32>,
+}
+
+/// Sets off an explosion at a world position. It goes off the next time
+/// `handle_explosions` runs, which carves the crater, throws nearby bodies
+/// and spawns the debris all at once.
+pub fn explode(
+    explosions: &mut EventWriter<Explosion>,
+    center: [i32; 3],
+    radius: f32,
+    strength: f32,
+) {
+    explosions.send(Explosion {
+        center,
+        radius,
+        strength,
+    });
+}
+
+/// Removes the voxels inside a noisy sphere around `center` and chars a shell
+/// around it, as one undoable step. Fluids are left alone. Returns what
+/// changed, the removed voxels are the changes with nothing after.
+///
+/// The edits go through `VoxWorld` like any other, which rebuilds the whole
+/// octree. Every voxel starts out unlit after that, so the lighting of the
+/// crater is traced again along with everything else.
+fn carve_crater(
+    world: &VoxWorld,
+    history: &mut EditHistory,
+    settings: &ExplosionSettings,
+    center: [i32; 3],
+    radius: f32,
+) -> Vec<VoxelChange> {
+    if radius <= 0.0 {
+        return Vec::new();
+    }
+    let noise = Perlin::new(center[0] as u32 ^ center[2] as u32);
+    let outer = radius * (1.0 + settings.roughness + settings.char_width);
+    let reach = outer.ceil() as i32;
+    let min = [center[0] - reach, center[1] - reach, center[2] - reach];
+    let max = [center[0] + reach, center[1] + reach, center[2] + reach];
+
+    let changes = world.edit_region(min, max, |pos, current| {
+        let vox = current?;
+        if fluid_of(vox).is_some() {
+            return Some(vox.clone());
+        }
+        let offset = Vec3::new(
+            (pos[0] - center[0]) as f32,
+            (pos[1] - center[1]) as f32,
+            (pos[2] - center[2]) as f32,
+        );
+        let wobble = noise.get([
+            pos[0] as f64 * 0.2,
+            pos[1] as f64 * 0.2,
+            pos[2] as f64 * 0.2,
+        ]) as f32;
+        let crater = radius * (1.0 + settings.roughness * wobble);
+        let distance = offset.length();
+        if distance < crater {
+            return None;
+        }
+
+        let shell = radius * settings.char_width;
+        let t = (distance - crater) / shell.max(0.001);
+        if t >= 1.0 {
+            return Some(vox.clone());
+        }
+        // darkest right at the edge of the crater
+        let shade = 0.25 + 0.75 * t;
+        Some(StorageVoxel {
+            color: vox.color.map(|c| (c as f32 * shade) as u8),
+            ..vox.clone()
+        })
+    });
+    history.record(changes.clone());
+    changes
+}
+
+/// Carves every `Explosion` into the world, throws nearby rigid bodies away
+/// from it and spawns sparks, smoke and glowing debris from the removed
+/// voxels.
+pub fn handle_explosions(
+    mut commands: Commands,
+    mut explosions: EventReader<Explosion>,
+    settings: Res<ExplosionSettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    mut history: ResMut<EditHistory>,
+    particle_settings: Res<ParticleSettings>,
+    mut particles: ResMut<Particles>,
+    mut bodies: Query<(&VoxelEntity, &mut RigidBody)>,
+) {
+    for explosion in explosions.read() {
+        let changes = carve_crater(
+            &world,
+            &mut history,
+            &settings,
+            explosion.center,
+            explosion.radius,
+        );
+        let center = origin.to_local(explosion.center) + Vec3::splat(0.5);
+
+        // falls off linearly to nothing at twice the radius
+        for (entity, mut body) in bodies.iter_mut() {
+            let offset = body.world_center(&entity.transform) - center;
+            let falloff = 1.0 - offset.length() / (explosion.radius * 2.0);
+            if falloff <= 0.0 {
+                continue;
+            }
+            let dir = offset.try_normalize().unwrap_or(Vec3::Y);
+            body.velocity += dir * explosion.strength * falloff;
+            body.wake();
+        }
+
+        let sparks = ParticleEmitter::sparks();
+        let smoke = ParticleEmitter::smoke();
+        let count = (explosion.radius * 12.0) as usize;
+        particles.burst(
+            &particle_settings,
+            &sparks.template,
+            center,
+            Vec3::splat(explosion.strength),
+            count,
+        );
+        particles.burst(
+            &particle_settings,
+            &smoke.template,
+            center,
+            Vec3::splat(explosion.radius),
+            count / 2,
+        );
+
+        if !settings.debris {
+            continue;
+        }
+        let removed: Vec<&VoxelChange> = changes
+            .iter()
+            .filter(|change| change.after.is_none())
+            .collect();
+        let step = (removed.len() / settings.max_debris.max(1)).max(1);
+        for change in removed.iter().step_by(step).take(settings.max_debris) {
+            let before = match &change.before {
+                Some(vox) => vox,
+                None => continue,
+            };
+            let position = origin.to_local(change.pos);
+            let jitter = Vec3::new(
+                rand::random::<f32>() - 0.5,
+                rand::random::<f32>(),
+                rand::random::<f32>() - 0.5,
+            );
+            let dir = (position + Vec3::splat(0.5) - center + jitter * 2.0)
+                .try_normalize()
+                .unwrap_or(Vec3::Y);
+
+            let mut color = before.color;
+            for i in 0..3 {
+                color[i] = ((color[i] as u16 + EMBER_COLOR[i] as u16) / 2) as u8;
+            }
+            let ember = StorageVoxel {
+                color,
+                emission: 1.0,
+                ..before.clone()
+            };
+            let debris = VoxelEntity {
+                name: "debris".to_string(),
+                transform: Transform::from_translation(position),
+                voxels: vec![([0, 0, 0], ember)],
+            };
+            let mut body = RigidBody::from_entity(&debris);
+            body.velocity = dir * explosion.strength * (0.5 + rand::random::<f32>());
+            // removed by `fade_debris` instead
+            body.merge_on_sleep = false;
+            // a trail of smoke while it glows, from just outside the voxel
+            // so it is not swallowed by it
+            let mut trail = ParticleEmitter::smoke();
+            trail.rate = 8.0;
+            trail.offset = Vec3::new(0.5, 1.5, 0.5);
+            trail.duration = Some(settings.debris_lifetime);
+            commands.spawn((
+                debris,
+                body,
+                Fading {
+                    age: 0.0,
+                    lifetime: settings.debris_lifetime,
+                    emission: vec![1.0],
+                },
+                trail,
+            ));
+        }
+    }
+}
+
+pub fn fade_debris(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut debris: Query<(Entity, &mut VoxelEntity, &mut Fading)>,
+) {
+    for (entity, mut vox_entity, mut fading) in debris.iter_mut() {
+        fading.age += time.delta_seconds();
+        if fading.age >= fading.lifetime {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let left = 1.0 - fading.age / fading.lifetime;
+        for ((_, vox), emission) in vox_entity.voxels.iter_mut().zip(fading.emission.iter()) {
+            vox.emission = emission * left;
+        }
+    }
+}
+
+/// `X` sets off an explosion at the first voxel in front of the camera.
+pub fn explosion_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<ExplosionSettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    camera: Query<&GlobalTransform, With<PCamera>>,
+    mut explosions: EventWriter<Explosion>,
+) {
+    if !keys.just_pressed(KeyCode::KeyX) {
+        return;
+    }
+    let camera = match camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    let start = origin.to_world(camera.translation());
+    let forward = camera.forward().as_vec3();
+    for i in 0..512 {
+        let point = (start + forward * i as f32 * 0.5).floor();
+        let pos = [point.x as i32, point.y as i32, point.z as i32];
+        if world.get_voxel(pos).is_some() {
+            explode(&mut explosions, pos, settings.radius, settings.strength);
+            return;
+        }
+    }
+}
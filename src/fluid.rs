@@ -0,0 +1,256 @@
+use bevy::{prelude::*, utils::HashMap, utils::HashSet};
+
+use crate::{
+    streaming::ChunkStreamer,
+    world_generator::{
+        chunk_of, chunks_near, world_position, EditSource, StorageVoxel, VoxWorld, VoxelsChanged,
+    },
+};
+
+/// Levels a fluid voxel can have, a full voxel has `FLUID_LEVELS`.
+pub const FLUID_LEVELS: u8 = 8;
+/// Voxel ids `WATER_ID..WATER_ID + FLUID_LEVELS` are water of level 1 and up,
+/// lava follows right after. Ids from `id_from_color` stay below both.
+pub const WATER_ID: u8 = 16;
+pub const LAVA_ID: u8 = WATER_ID + FLUID_LEVELS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fluid {
+    Water,
+    Lava,
+}
+impl Fluid {
+    pub fn id(self: Self, level: u8) -> u8 {
+        let base = match self {
+            Fluid::Water => WATER_ID,
+            Fluid::Lava => LAVA_ID,
+        };
+        base + level.clamp(1, FLUID_LEVELS) - 1
+    }
+}
+
+/// The fluid and level of a voxel, `None` for anything solid.
+pub fn fluid_of(vox: &StorageVoxel) -> Option<(Fluid, u8)> {
+    if (WATER_ID..LAVA_ID).contains(&vox.id) {
+        Some((Fluid::Water, vox.id - WATER_ID + 1))
+    } else if (LAVA_ID..LAVA_ID + FLUID_LEVELS).contains(&vox.id) {
+        Some((Fluid::Lava, vox.id - LAVA_ID + 1))
+    } else {
+        None
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct FluidSettings {
+    pub enabled: bool,
+    /// Simulation steps per second.
+    pub tick_rate: f32,
+    /// Lava only moves on every this many ticks.
+    pub lava_interval: u32,
+    /// Chunks simulated per tick, the rest wait for the next one.
+    pub max_chunks: usize,
+}
+impl Default for FluidSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tick_rate: 8.0,
+            lava_interval: 4,
+            max_chunks: 64,
+        }
+    }
+}
+
+/// Chunks that may hold moving fluid. A chunk goes idle once nothing in it
+/// moves and is woken again by edits in or next to it.
+#[derive(Resource, Default)]
+pub struct ActiveFluids {
+    pub chunks: HashSet<[i32; 3]>,
+    /// Loaded chunks that have been looked at once, so fluid in newly loaded
+    /// chunks starts flowing without an edit.
+    seen: HashSet<[i32; 3]>,
+    timer: f32,
+    tick: u32,
+}
+
+/// Wakes chunks touched by edits, and chunks that were just loaded.
+pub fn wake_fluids(
+    world: Res<VoxWorld>,
+    mut changes: EventReader<VoxelsChanged>,
+    mut active: ResMut<ActiveFluids>,
+) {
+    let chunks = world.world.read().unwrap();
+    let active = &mut *active;
+    active.seen.retain(|pos| chunks.get(*pos).is_some());
+    for (pos, _) in chunks.iter() {
+        if active.seen.insert(pos) {
+            active.chunks.insert(pos);
+        }
+    }
+
+    for change in changes.read() {
+        active.chunks.extend(chunks_near(change, &chunks));
+    }
+}
+
+/// Steps the fluid in active chunks at `FluidSettings::tick_rate`. Fluid
+/// falls first, then spreads one level at a time to lower neighbours, so
+/// the total amount stays the same. The edits go through `VoxWorld::apply`
+/// like any other, which wakes the chunks they touch for the next tick.
+pub fn simulate_fluids(
+    time: Res<Time>,
+    settings: Res<FluidSettings>,
+    world: Res<VoxWorld>,
+    streamer: Res<ChunkStreamer>,
+    mut active: ResMut<ActiveFluids>,
+) {
+    if !settings.enabled || settings.tick_rate <= 0.0 {
+        return;
+    }
+    active.timer += time.delta_seconds();
+    let tick_time = 1.0 / settings.tick_rate;
+    if active.timer < tick_time {
+        return;
+    }
+    active.timer = 0.0;
+    active.tick = active.tick.wrapping_add(1);
+    let tick = active.tick;
+
+    let chunks: Vec<[i32; 3]> = active
+        .chunks
+        .iter()
+        .take(settings.max_chunks)
+        .copied()
+        .collect();
+    for pos in chunks.iter() {
+        active.chunks.remove(pos);
+    }
+
+    let mut cells = Vec::new();
+    {
+        let world = world.world.read().unwrap();
+        for chunk_pos in chunks.iter() {
+            if let Some(chunk) = world.get(*chunk_pos) {
+                for (local, vox) in chunk.iter() {
+                    if let Some((fluid, _)) = fluid_of(vox) {
+                        if fluid == Fluid::Lava && tick % settings.lava_interval.max(1) != 0 {
+                            // still moving, just not this tick
+                            active.chunks.insert(*chunk_pos);
+                            continue;
+                        }
+                        cells.push(world_position(*chunk_pos, local));
+                    }
+                }
+            }
+        }
+    }
+    if cells.is_empty() {
+        return;
+    }
+    // bottom up, so a falling column moves as one
+    cells.sort_by_key(|pos| pos[1]);
+
+    let mut flow = Flow {
+        world: &world,
+        streamer: &streamer,
+        changed: HashMap::new(),
+    };
+    for pos in cells {
+        flow.step(pos, tick);
+    }
+
+    let edits: Vec<([i32; 3], Option<StorageVoxel>)> = flow.changed.into_iter().collect();
+    world.apply_from(edits, EditSource::Simulation);
+}
+
+/// Pending fluid edits of one tick on top of the world.
+struct Flow<'a> {
+    world: &'a VoxWorld,
+    streamer: &'a ChunkStreamer,
+    changed: HashMap<[i32; 3], Option<StorageVoxel>>,
+}
+impl<'a> Flow<'a> {
+    /// Fluid never leaves the chunks that have arrived. Flowing into one that
+    /// is still loading would create it in `VoxWorld`, and the saved chunk
+    /// would then be dropped when it comes in.
+    fn is_open(self: &Self, pos: [i32; 3]) -> bool {
+        self.streamer.has_arrived(chunk_of(pos).0)
+    }
+
+    fn get(self: &Self, pos: [i32; 3]) -> Option<StorageVoxel> {
+        match self.changed.get(&pos) {
+            Some(vox) => vox.clone(),
+            None => self.world.get_voxel(pos),
+        }
+    }
+
+    fn set_level(self: &mut Self, pos: [i32; 3], source: &StorageVoxel, fluid: Fluid, level: u8) {
+        let vox = match level {
+            0 => None,
+            level => Some(StorageVoxel {
+                id: fluid.id(level),
+                ..source.clone()
+            }),
+        };
+        self.changed.insert(pos, vox);
+    }
+
+    fn step(self: &mut Self, pos: [i32; 3], tick: u32) {
+        let vox = match self.get(pos) {
+            Some(vox) => vox,
+            None => return,
+        };
+        let (fluid, mut level) = match fluid_of(&vox) {
+            Some(fluid) => fluid,
+            None => return,
+        };
+
+        // fall
+        let below = [pos[0], pos[1] - 1, pos[2]];
+        let room = match self.get(below) {
+            None if self.is_open(below) => FLUID_LEVELS,
+            None => 0,
+            Some(other) => match fluid_of(&other) {
+                Some((f, l)) if f == fluid => FLUID_LEVELS - l,
+                _ => 0,
+            },
+        };
+        if room > 0 {
+            let moved = room.min(level);
+            let below_level = FLUID_LEVELS - room + moved;
+            self.set_level(below, &vox, fluid, below_level);
+            level -= moved;
+            self.set_level(pos, &vox, fluid, level);
+            if level == 0 {
+                return;
+            }
+        }
+
+        // spread, starting from a different side every tick so it stays even
+        let sides = [[1, 0], [0, 1], [-1, 0], [0, -1]];
+        let mut spread = false;
+        for k in 0..4 {
+            if level <= 1 {
+                break;
+            }
+            let [dx, dz] = sides[(k + tick as usize) % 4];
+            let side = [pos[0] + dx, pos[1], pos[2] + dz];
+            let side_level = match self.get(side) {
+                None if self.is_open(side) => 0,
+                None => continue,
+                Some(other) => match fluid_of(&other) {
+                    Some((f, l)) if f == fluid => l,
+                    _ => continue,
+                },
+            };
+            if side_level + 1 < level {
+                self.set_level(side, &vox, fluid, side_level + 1);
+                level -= 1;
+                spread = true;
+            }
+        }
+        if spread {
+            self.set_level(pos, &vox, fluid, level);
+        }
+    }
+}
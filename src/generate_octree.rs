@@ -9,11 +9,10 @@ use bevy::prelude::*;
 use crate::{
     compute::ComputeOctree,
     octree::{get_lod, Octree},
+    particles::Particles,
     player_controller::{PCamera, Player},
-    world_generator::{
-        get_u8_color, id_from_color, StorageVoxel, VoxWorld, VoxelEntity, C_SIZE, ENTITYDRAW,
-        RENDERDIST, W_WIDTH,
-    },
+    streaming::FloatingOrigin,
+    world_generator::{VoxWorld, VoxelEntity, C_SIZE, ENTITYDRAW, RENDERDIST, W_WIDTH},
 };
 
 #[derive(Event)]
@@ -24,7 +23,7 @@ pub struct Trigger(pub Arc<Mutex<bool>>);
 
 pub fn setup(mut commands: Commands) {
     let width = W_WIDTH as f32;
-    let lock = Arc::new(Mutex::new(Some(Octree::new(width * 2.0, [width; 3]))));
+    let lock = Arc::new(Mutex::new(Some(Octree::new(width * 2.0, [0.0; 3]))));
     commands.insert_resource(ComputeOctree(lock));
 
     let lock = Arc::new(Mutex::new(true));
@@ -48,8 +47,10 @@ pub fn run_octree(
 pub fn create_octree(
     world: Res<VoxWorld>,
     vox_entities: Query<&VoxelEntity>,
+    particles: Res<Particles>,
     shader_octree: Res<ComputeOctree>,
     cam_query: Query<&GlobalTransform, (With<PCamera>, Without<Player>)>,
+    origin: Res<FloatingOrigin>,
     trigger: Res<Trigger>,
     mut event_reader: EventReader<GenerateOctreeEvent>,
 ) {
@@ -70,32 +71,40 @@ pub fn create_octree(
                             vox_entity_data.push(vox_entity.clone());
                         }
                     }
+                    let particle_data: Vec<_> = particles
+                        .particles
+                        .iter()
+                        .filter(|p| p.position.distance(cam_pos) < ENTITYDRAW as f32)
+                        .map(|p| (p.position, p.voxel().into_normal()))
+                        .collect();
 
                     let trig_clone = Arc::clone(&trigger.0);
                     let world_clone = Arc::clone(&world.world);
                     let octree_clone = Arc::clone(&shader_octree.0);
+                    let origin = *origin;
                     thread::spawn(move || {
                         let noww = Instant::now();
 
+                        // the octree is built in local coordinates around the
+                        // floating origin, same as the camera
                         let width = W_WIDTH as f32;
-                        let root = [width; 3];
-                        let mut new_octree = Octree::new(width * 2.0, root);
-                        let mut world = world_clone.write().unwrap();
+                        let mut new_octree = Octree::new(width * 2.0, [0.0; 3]);
+                        let cam_world = origin.to_world(cam_pos);
+                        let cam_chunk = [
+                            (cam_world.x / C_SIZE as f32).floor() as i32,
+                            (cam_world.y / C_SIZE as f32).floor() as i32,
+                            (cam_world.z / C_SIZE as f32).floor() as i32,
+                        ];
+                        let world = world_clone.read().unwrap();
 
                         let start = -((RENDERDIST / C_SIZE) as i32);
                         let end = (RENDERDIST / C_SIZE) as i32;
                         for cx in start..end {
                             for cy in start..end {
                                 for cz in start..end {
-                                    let x = ((cam_pos.x as i32 / C_SIZE as i32) + cx)
-                                        .min(((W_WIDTH * 2) as i32 / C_SIZE as i32) - 1)
-                                        .max(0);
-                                    let y = ((cam_pos.y as i32 / C_SIZE as i32) + cy)
-                                        .min(((W_WIDTH * 2) as i32 / C_SIZE as i32) - 1)
-                                        .max(0);
-                                    let z = ((cam_pos.z as i32 / C_SIZE as i32) + cz)
-                                        .min(((W_WIDTH * 2) as i32 / C_SIZE as i32) - 1)
-                                        .max(0);
+                                    let x = cam_chunk[0] + cx;
+                                    let y = cam_chunk[1] + cy;
+                                    let z = cam_chunk[2] + cz;
 
                                     // let chunk_pos = Vec3::new(
                                     //     (x * C_SIZE as i32) as f32,
@@ -108,41 +117,32 @@ pub fn create_octree(
                                     // if dot_product > 0.0 || chunk_pos.distance(cam_pos) < 128.0 {
 
                                     // }
+                                    let chunk = match world.get([x, y, z]) {
+                                        Some(chunk) => chunk,
+                                        None => continue,
+                                    };
+
                                     let mut counter = 0;
-                                    for (vox_pos, vox) in
-                                        world[x as usize][y as usize][z as usize].voxels.iter_mut()
-                                    {
-                                        let lod = get_lod(
-                                            Vec3::new(
-                                                vox_pos[0] as f32,
-                                                vox_pos[1] as f32,
-                                                vox_pos[2] as f32,
-                                            ),
-                                            cam_pos,
-                                        );
+                                    for (local, vox) in chunk.iter() {
+                                        let vox_pos = origin
+                                            .to_local([
+                                                x * C_SIZE as i32 + local[0] as i32,
+                                                y * C_SIZE as i32 + local[1] as i32,
+                                                z * C_SIZE as i32 + local[2] as i32,
+                                            ])
+                                            .to_array();
+                                        let lod = get_lod(Vec3::from_array(vox_pos), cam_pos);
 
                                         match lod {
                                             1 => {
                                                 counter = 0;
-                                                new_octree.insert(
-                                                    [
-                                                        vox_pos[0] as f32,
-                                                        vox_pos[1] as f32,
-                                                        vox_pos[2] as f32,
-                                                    ],
-                                                    vox.into_normal(),
-                                                    lod,
-                                                );
+                                                new_octree.insert(vox_pos, vox.into_normal(), lod);
                                             }
                                             2 => {
                                                 if counter >= 1 {
                                                     counter = 0;
                                                     new_octree.insert(
-                                                        [
-                                                            vox_pos[0] as f32,
-                                                            vox_pos[1] as f32,
-                                                            vox_pos[2] as f32,
-                                                        ],
+                                                        vox_pos,
                                                         vox.into_normal(),
                                                         lod,
                                                     );
@@ -152,11 +152,7 @@ pub fn create_octree(
                                                 if counter >= 2 {
                                                     counter = 0;
                                                     new_octree.insert(
-                                                        [
-                                                            vox_pos[0] as f32,
-                                                            vox_pos[1] as f32,
-                                                            vox_pos[2] as f32,
-                                                        ],
+                                                        vox_pos,
                                                         vox.into_normal(),
                                                         lod,
                                                     );
@@ -166,11 +162,7 @@ pub fn create_octree(
                                                 if counter >= 6 {
                                                     counter = 0;
                                                     new_octree.insert(
-                                                        [
-                                                            vox_pos[0] as f32,
-                                                            vox_pos[1] as f32,
-                                                            vox_pos[2] as f32,
-                                                        ],
+                                                        vox_pos,
                                                         vox.into_normal(),
                                                         lod,
                                                     );
@@ -180,11 +172,7 @@ pub fn create_octree(
                                                 if counter >= 12 {
                                                     counter = 0;
                                                     new_octree.insert(
-                                                        [
-                                                            vox_pos[0] as f32,
-                                                            vox_pos[1] as f32,
-                                                            vox_pos[2] as f32,
-                                                        ],
+                                                        vox_pos,
                                                         vox.into_normal(),
                                                         lod,
                                                     );
@@ -199,23 +187,10 @@ pub fn create_octree(
                         }
 
                         for vox_entity in vox_entity_data.iter() {
-                            for voxel in vox_entity.voxels.iter() {
-                                let x = vox_entity.transform.translation.x + voxel.x as f32;
-                                let y = vox_entity.transform.translation.y + voxel.z as f32;
-                                let z = vox_entity.transform.translation.z + voxel.y as f32;
-
-                                let vox_color = vox_entity.palette[voxel.i as usize];
-                                let color = get_u8_color(vox_color);
-                                let id = id_from_color([vox_color.r, vox_color.r, vox_color.b]);
-                                let emission = vox_entity.materials[voxel.i as usize]
-                                    .emission()
-                                    .unwrap_or(0.0);
-
-                                let vox = StorageVoxel {
-                                    id,
-                                    color,
-                                    emission,
-                                };
+                            for (offset, vox) in vox_entity.voxels.iter() {
+                                let Vec3 { x, y, z } = vox_entity.transform.transform_point(
+                                    Vec3::new(offset[0] as f32, offset[1] as f32, offset[2] as f32),
+                                );
 
                                 new_octree.insert(
                                     [x, y, z],
@@ -225,6 +200,10 @@ pub fn create_octree(
                             }
                         }
 
+                        for (pos, voxel) in particle_data {
+                            new_octree.insert(pos.to_array(), voxel, get_lod(pos, cam_pos));
+                        }
+
                         *trig_clone.lock().unwrap() = true;
                         *octree_clone.lock().unwrap() = Some(new_octree);
 
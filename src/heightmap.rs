@@ -0,0 +1,187 @@
+use std::{io, path::PathBuf};
+
+use bevy::prelude::*;
+
+use crate::{
+    fluid::{Fluid, FLUID_LEVELS},
+    region::invalid_data,
+    world_generator::{color_voxel, StorageVoxel, WorldData},
+};
+
+/// Terrain from a grayscale heightmap image, one voxel column per pixel. The
+/// image x axis runs along world x, the image y axis along world z.
+#[derive(Resource, Clone)]
+pub struct HeightmapSettings {
+    /// No heightmap is loaded while this is `None`.
+    pub height_map: Option<PathBuf>,
+    /// Optional RGB image for the surface colour, stretched over the
+    /// heightmap if the sizes differ.
+    pub color_map: Option<PathBuf>,
+    /// Height in voxels of a white pixel, black is at `offset[1]`.
+    pub vertical_scale: f32,
+    /// Colour below the surface, and of the surface without a colour map.
+    pub base_color: [u8; 3],
+    /// Voxels filled below the surface. Columns next to a cliff go down to
+    /// the foot of it so there are no gaps.
+    pub fill_depth: i32,
+    /// Empty space up to this height, relative to `offset[1]`, is filled with
+    /// water that flows like any other.
+    pub water_level: Option<i32>,
+    pub water_color: [u8; 3],
+    /// World position of the heightmap's top left corner at height 0.
+    pub offset: [i32; 3],
+}
+impl Default for HeightmapSettings {
+    fn default() -> Self {
+        Self {
+            height_map: None,
+            color_map: None,
+            vertical_scale: 128.0,
+            base_color: [90, 90, 90],
+            fill_depth: 4,
+            water_level: None,
+            water_color: [38, 67, 190],
+            offset: [0, -128, 0],
+        }
+    }
+}
+
+/// Turns the heightmap into voxel columns in `world`, returns how many voxels
+/// were placed.
+pub fn load_heightmap(settings: &HeightmapSettings, world: &mut WorldData) -> io::Result<usize> {
+    let path = match &settings.height_map {
+        Some(path) => path,
+        None => return Ok(0),
+    };
+    let heights = image::open(path)
+        .map_err(|err| invalid_data(&err.to_string()))?
+        .to_luma16();
+    let colors = match &settings.color_map {
+        Some(path) => Some(
+            image::open(path)
+                .map_err(|err| invalid_data(&err.to_string()))?
+                .to_rgb8(),
+        ),
+        None => None,
+    };
+
+    let (width, depth) = heights.dimensions();
+    let height = |x: u32, z: u32| {
+        (heights.get_pixel(x, z).0[0] as f32 / u16::MAX as f32 * settings.vertical_scale).round()
+            as i32
+    };
+    let base = voxel(settings.base_color);
+    let water = StorageVoxel {
+        id: Fluid::Water.id(FLUID_LEVELS),
+        ..voxel(settings.water_color)
+    };
+
+    let mut placed = 0;
+    for z in 0..depth {
+        for x in 0..width {
+            let top = height(x, z);
+            let mut bottom = top - settings.fill_depth;
+            for (nx, nz) in [
+                (x.wrapping_sub(1), z),
+                (x + 1, z),
+                (x, z.wrapping_sub(1)),
+                (x, z + 1),
+            ] {
+                if nx < width && nz < depth {
+                    bottom = bottom.min(height(nx, nz) + 1);
+                }
+            }
+
+            let surface = match &colors {
+                Some(colors) => {
+                    let cx = x * colors.width() / width;
+                    let cz = z * colors.height() / depth;
+                    voxel(colors.get_pixel(cx, cz).0)
+                }
+                None => base.clone(),
+            };
+
+            let column = |y: i32| {
+                [
+                    settings.offset[0] + x as i32,
+                    settings.offset[1] + y,
+                    settings.offset[2] + z as i32,
+                ]
+            };
+            for y in bottom..top {
+                world.data.set_voxel(column(y), Some(base.clone()));
+            }
+            world.data.set_voxel(column(top), Some(surface));
+            placed += (top - bottom + 1) as usize;
+
+            if let Some(level) = settings.water_level {
+                for y in top + 1..=level {
+                    world.data.set_voxel(column(y), Some(water.clone()));
+                    placed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(placed)
+}
+
+fn voxel(color: [u8; 3]) -> StorageVoxel {
+    let [r, g, b] = color;
+    color_voxel(dot_vox::Color { r, g, b, a: 255 }, 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn columns_follow_the_heightmap() {
+        // heights of 2, 8, 2 along z = 0 and 2, 2, 0 along z = 1, the colour
+        // map is one pixel wide with red above green
+        let settings = HeightmapSettings {
+            height_map: Some(fixture("heightmap.png")),
+            color_map: Some(fixture("colormap.png")),
+            vertical_scale: 8.0,
+            fill_depth: 1,
+            water_level: Some(3),
+            offset: [0, 0, 0],
+            ..Default::default()
+        };
+        let mut world = WorldData::default();
+        assert_eq!(load_heightmap(&settings, &mut world).unwrap(), 23);
+        let get = |pos: [i32; 3]| world.data.get_voxel(pos).cloned();
+        let base = voxel(settings.base_color);
+
+        // surfaces sample the stretched colour map
+        assert_eq!(get([0, 2, 0]), Some(voxel([200, 0, 0])));
+        assert_eq!(get([2, 0, 1]), Some(voxel([0, 200, 0])));
+        assert_eq!(get([0, 1, 0]), Some(base.clone()));
+        assert_eq!(get([0, 0, 0]), None);
+
+        // the cliff goes down to just above the foot of its lowest neighbour
+        assert_eq!(get([1, 8, 0]), Some(voxel([200, 0, 0])));
+        assert_eq!(get([1, 3, 0]), Some(base.clone()));
+        assert_eq!(get([1, 2, 0]), None);
+        assert_eq!(get([1, 9, 0]), None);
+
+        // water fills the low corner up to the water level
+        for y in 1..=3 {
+            assert_eq!(
+                get([2, y, 1]).map(|vox| vox.id),
+                Some(Fluid::Water.id(FLUID_LEVELS))
+            );
+        }
+        assert_eq!(get([2, 4, 1]), None);
+        assert_eq!(get([2, -1, 1]), Some(base));
+        assert_eq!(get([2, -2, 1]), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{error::Error, fmt, io};
+
+/// Why part of a world could not be loaded.
+#[derive(Debug)]
+pub enum WorldLoadError {
+    Io(io::Error),
+    /// The file could not be parsed, or its contents contradict each other.
+    BadFile {
+        path: String,
+        reason: String,
+    },
+    /// A voxel outside its model or beyond `WORLD_LIMIT`.
+    OutOfBounds {
+        position: [i32; 3],
+    },
+    /// A voxel whose colour index is missing from the palette.
+    BadPaletteIndex {
+        index: u8,
+        palette_len: usize,
+    },
+}
+impl WorldLoadError {
+    pub fn bad_file(path: &str, reason: impl Into<String>) -> Self {
+        WorldLoadError::BadFile {
+            path: path.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+impl fmt::Display for WorldLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldLoadError::Io(err) => write!(f, "{}", err),
+            WorldLoadError::BadFile { path, reason } => write!(f, "{}: {}", path, reason),
+            WorldLoadError::OutOfBounds { position } => {
+                write!(f, "voxel at {:?} is out of bounds", position)
+            }
+            WorldLoadError::BadPaletteIndex { index, palette_len } => write!(
+                f,
+                "palette index {} is out of range, the palette has {} colours",
+                index, palette_len
+            ),
+        }
+    }
+}
+impl Error for WorldLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorldLoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for WorldLoadError {
+    fn from(err: io::Error) -> Self {
+        WorldLoadError::Io(err)
+    }
+}
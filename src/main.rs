@@ -4,18 +4,56 @@ use bevy::{
     window::WindowMode,
 };
 use compute::RayTracerPlugin;
+use edit_history::{undo_redo_keys, EditHistory};
+use explosion::{explosion_keys, fade_debris, handle_explosions, Explosion, ExplosionSettings};
+use fluid::{simulate_fluids, wake_fluids, ActiveFluids, FluidSettings};
 use generate_octree::{create_octree, run_octree, GenerateOctreeEvent};
+use heightmap::HeightmapSettings;
+use mesh_export::mesh_export_keys;
+use particles::{emit_particles, simulate_particles, ParticleSettings, Particles};
 use player_controller::{
-    initial_grab_cursor, move_player, player_look, spawn_player, InputState, MovementSettings,
+    initial_grab_cursor, move_player, player_look, spawn_player, toggle_walk_mode, InputState,
+    MovementSettings, WalkState,
 };
 use pre_compute::{setup_shader_screen, update_shader_screen};
-use world_generator::{build_world, receive_world, VoxWorld};
+use rigid_body::{merge_sleeping_bodies, step_rigid_bodies, throw_props, RigidBodySettings};
+use streaming::{FloatingOrigin, StreamingSettings};
+use vox_animation::play_vox_animations;
+use vox_asset::{build_vox_scenes, VoxModel, VoxModelLoader};
+use vox_export::{export_keys, ExportSettings};
+use voxel_physics::{
+    detach_islands, simulate_granular, wake_granular, ActiveGranular, PhysicsSettings,
+};
+use world_generator::{
+    build_world, rebuild_octree_on_change, receive_world, send_voxel_changes, ModelImports,
+    TerrainSettings, VoxWorld, VoxelsChanged,
+};
 
+mod binvox;
+mod chunk;
 mod compute;
+mod edit_history;
+mod explosion;
+mod fluid;
 mod generate_octree;
+mod heightmap;
+mod load_error;
+mod material;
+mod mesh_export;
 mod octree;
+mod particles;
 mod player_controller;
 mod pre_compute;
+mod qubicle;
+mod region;
+mod rigid_body;
+mod schematic;
+mod streaming;
+mod vox_animation;
+mod vox_asset;
+mod vox_export;
+mod voxel_physics;
+mod voxelizer;
 mod world_generator;
 
 fn main() {
@@ -35,16 +73,39 @@ fn main() {
             FrameTimeDiagnosticsPlugin::default(),
             LogDiagnosticsPlugin::default(),
         ))
+        .init_asset::<VoxModel>()
+        .init_asset_loader::<VoxModelLoader>()
         .add_event::<GenerateOctreeEvent>()
+        .add_event::<VoxelsChanged>()
+        .add_event::<Explosion>()
         .init_resource::<MovementSettings>()
         .init_resource::<InputState>()
+        .init_resource::<WalkState>()
         .init_resource::<VoxWorld>()
+        .init_resource::<EditHistory>()
+        .init_resource::<StreamingSettings>()
+        .init_resource::<FloatingOrigin>()
+        .init_resource::<TerrainSettings>()
+        .init_resource::<HeightmapSettings>()
+        .init_resource::<ModelImports>()
+        .init_resource::<FluidSettings>()
+        .init_resource::<ActiveFluids>()
+        .init_resource::<PhysicsSettings>()
+        .init_resource::<ActiveGranular>()
+        .init_resource::<RigidBodySettings>()
+        .init_resource::<ExplosionSettings>()
+        .init_resource::<ParticleSettings>()
+        .init_resource::<Particles>()
+        .init_resource::<ExportSettings>()
         .add_systems(
             Startup,
             (
                 pre_compute::setup,
                 generate_octree::setup,
                 world_generator::setup,
+                region::setup,
+                apply_deferred,
+                streaming::setup,
                 initial_grab_cursor,
                 setup_shader_screen,
                 apply_deferred,
@@ -56,10 +117,41 @@ fn main() {
         .add_systems(
             Update,
             (
+                build_vox_scenes,
                 receive_world,
+                streaming::receive_chunks,
+                region::save_dirty_chunks,
+                toggle_walk_mode,
                 move_player,
                 player_look,
+                streaming::recenter_origin,
+                streaming::stream_chunks,
+                streaming::unload_chunks,
                 update_shader_screen,
+                (
+                    undo_redo_keys,
+                    throw_props,
+                    explosion_keys,
+                    handle_explosions,
+                    export_keys,
+                    mesh_export_keys,
+                )
+                    .chain(),
+                play_vox_animations,
+                (simulate_fluids, simulate_granular).chain(),
+                send_voxel_changes,
+                (
+                    wake_fluids,
+                    wake_granular,
+                    detach_islands,
+                    step_rigid_bodies,
+                    merge_sleeping_bodies,
+                    fade_debris,
+                    emit_particles,
+                    simulate_particles,
+                )
+                    .chain(),
+                rebuild_octree_on_change,
                 run_octree,
                 create_octree,
             )
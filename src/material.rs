@@ -0,0 +1,323 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use bevy::{prelude::*, render::render_resource::ShaderType, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::region::invalid_data;
+
+/// Size of the material buffer on the GPU.
+pub const MAX_MATERIALS: usize = 4096;
+/// Saved tables start with `MATERIAL_MAGIC` and this version, anything else
+/// is rejected.
+pub const MATERIAL_VERSION: u32 = 1;
+const MATERIAL_MAGIC: [u8; 4] = *b"VXMT";
+
+/// MagicaVoxel material types, the `_type` property of a `MATL` chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaterialKind {
+    Diffuse,
+    Metal,
+    Glass,
+    Emit,
+    Blend,
+    Media,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoxMaterial {
+    pub kind: MaterialKind,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub ior: f32,
+    pub transparency: f32,
+    pub emission: f32,
+    /// Falls when nothing is below it, like sand or gravel.
+    pub granular: bool,
+}
+impl Default for VoxMaterial {
+    fn default() -> Self {
+        VoxMaterial {
+            kind: MaterialKind::Diffuse,
+            roughness: 1.0,
+            metalness: 0.0,
+            ior: 1.3,
+            transparency: 0.0,
+            emission: 0.0,
+            granular: false,
+        }
+    }
+}
+impl VoxMaterial {
+    /// Reads the properties of a `.vox` `MATL` chunk, missing ones keep their
+    /// defaults.
+    pub fn from_vox(material: &dot_vox::Material) -> Self {
+        let props = &material.properties;
+        let number = |key: &str| props.get(key).and_then(|v| v.parse::<f32>().ok());
+        let default = VoxMaterial::default();
+
+        let kind = match props.get("_type").map(|t| t.as_str()) {
+            Some("_metal") => MaterialKind::Metal,
+            Some("_glass") => MaterialKind::Glass,
+            Some("_emit") => MaterialKind::Emit,
+            Some("_blend") => MaterialKind::Blend,
+            Some("_media") => MaterialKind::Media,
+            _ => MaterialKind::Diffuse,
+        };
+        VoxMaterial {
+            kind,
+            roughness: number("_rough").unwrap_or(default.roughness),
+            metalness: number("_metal").unwrap_or(default.metalness),
+            // MagicaVoxel stores the index of refraction minus one
+            ior: number("_ior").map(|ior| ior + 1.0).unwrap_or(default.ior),
+            transparency: number("_trans")
+                .or_else(|| number("_alpha"))
+                .unwrap_or(default.transparency),
+            emission: match kind {
+                MaterialKind::Emit => number("_emit").unwrap_or(0.0),
+                _ => 0.0,
+            },
+            granular: false,
+        }
+    }
+}
+
+/// The `MATL` chunk of a 0 based palette index. Material ids count from 1
+/// like the indices in `XYZI`, and files may leave entries out.
+pub fn vox_material(materials: &[dot_vox::Material], index: usize) -> Option<&dot_vox::Material> {
+    materials.iter().find(|m| m.id as usize == index + 1)
+}
+
+/// One material as the shaders see it, `kind` in the order of `MaterialKind`.
+#[derive(Clone, Copy, Default, ShaderType)]
+pub struct ShaderMaterial {
+    pub kind: u32,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub ior: f32,
+    pub transparency: f32,
+    pub emission: f32,
+}
+
+/// Every material in use, `StorageVoxel::material` indexes into it. Entries
+/// are only ever appended so indices stay valid, and index 0 is the default
+/// diffuse material. The table is saved with the world.
+#[derive(Resource, Clone)]
+pub struct MaterialTable {
+    materials: Arc<RwLock<Materials>>,
+}
+impl Default for MaterialTable {
+    fn default() -> Self {
+        MaterialTable {
+            materials: Arc::new(RwLock::new(Materials::new(vec![VoxMaterial::default()]))),
+        }
+    }
+}
+
+/// Materials by index, and the index of each material for `index_of`.
+struct Materials {
+    list: Vec<VoxMaterial>,
+    lookup: HashMap<MaterialKey, u16>,
+}
+impl Materials {
+    fn new(list: Vec<VoxMaterial>) -> Self {
+        let mut lookup = HashMap::new();
+        for (i, material) in list.iter().enumerate() {
+            lookup.entry(material_key(material)).or_insert(i as u16);
+        }
+        Materials { list, lookup }
+    }
+}
+
+/// The bits of every field, so materials can be hashed.
+type MaterialKey = [u32; 7];
+fn material_key(material: &VoxMaterial) -> MaterialKey {
+    [
+        material.kind as u32,
+        material.roughness.to_bits(),
+        material.metalness.to_bits(),
+        material.ior.to_bits(),
+        material.transparency.to_bits(),
+        material.emission.to_bits(),
+        material.granular as u32,
+    ]
+}
+
+impl MaterialTable {
+    /// Reads a saved table, a missing file gives the default table.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            return Ok(MaterialTable::default());
+        }
+        let bytes = fs::read(path)?;
+        if bytes.len() < 8 || bytes[0..4] != MATERIAL_MAGIC {
+            return Err(invalid_data("not a material table"));
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != MATERIAL_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported material version {} (expected {})",
+                version, MATERIAL_VERSION
+            )));
+        }
+        let materials: Vec<VoxMaterial> =
+            bincode::deserialize(&bytes[8..]).map_err(|err| invalid_data(&err.to_string()))?;
+        if materials.is_empty() {
+            return Err(invalid_data("material table is empty"));
+        }
+        Ok(MaterialTable {
+            materials: Arc::new(RwLock::new(Materials::new(materials))),
+        })
+    }
+
+    pub fn save(self: &Self, path: &Path) -> io::Result<()> {
+        let mut bytes = MATERIAL_MAGIC.to_vec();
+        bytes.extend(MATERIAL_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut bytes, &self.materials.read().unwrap().list)
+            .map_err(|err| invalid_data(&err.to_string()))?;
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    /// Number of materials, which only ever grows.
+    pub fn count(self: &Self) -> usize {
+        self.materials.read().unwrap().list.len()
+    }
+
+    /// Whether each material is granular, by index.
+    pub fn granular(self: &Self) -> Vec<bool> {
+        self.materials
+            .read()
+            .unwrap()
+            .list
+            .iter()
+            .map(|m| m.granular)
+            .collect()
+    }
+
+    pub fn get(self: &Self, index: u16) -> VoxMaterial {
+        self.materials
+            .read()
+            .unwrap()
+            .list
+            .get(index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Finds or adds a material. Once the table is full the default material
+    /// is used instead.
+    pub fn index_of(self: &Self, material: VoxMaterial) -> u16 {
+        let key = material_key(&material);
+        if let Some(i) = self.materials.read().unwrap().lookup.get(&key) {
+            return *i;
+        }
+        let mut materials = self.materials.write().unwrap();
+        // another thread may have added it in between
+        if let Some(i) = materials.lookup.get(&key) {
+            return *i;
+        }
+        if materials.list.len() >= MAX_MATERIALS {
+            info!("material table is full, using the default material");
+            return 0;
+        }
+        let index = materials.list.len() as u16;
+        materials.list.push(material);
+        materials.lookup.insert(key, index);
+        index
+    }
+
+    /// Table indices for the 256 palette entries of a `.vox` file.
+    pub fn register_vox(self: &Self, materials: &[dot_vox::Material]) -> Vec<u16> {
+        (0..256)
+            .map(|i| match vox_material(materials, i) {
+                Some(material) => self.index_of(VoxMaterial::from_vox(material)),
+                None => 0,
+            })
+            .collect()
+    }
+
+    pub fn to_shader(self: &Self) -> Vec<ShaderMaterial> {
+        self.materials
+            .read()
+            .unwrap()
+            .list
+            .iter()
+            .map(|m| ShaderMaterial {
+                kind: m.kind as u32,
+                roughness: m.roughness,
+                metalness: m.metalness,
+                ior: m.ior,
+                transparency: m.transparency,
+                emission: m.emission,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("materials_{}_{}.bin", name, std::process::id()))
+    }
+
+    fn metal() -> VoxMaterial {
+        VoxMaterial {
+            kind: MaterialKind::Metal,
+            roughness: 0.2,
+            metalness: 1.0,
+            ..VoxMaterial::default()
+        }
+    }
+
+    #[test]
+    fn index_of_finds_materials_it_added() {
+        let table = MaterialTable::default();
+        assert_eq!(table.index_of(VoxMaterial::default()), 0);
+        let metal_index = table.index_of(metal());
+        assert_eq!(metal_index, 1);
+        assert_eq!(table.index_of(metal()), metal_index);
+        assert_eq!(table.count(), 2);
+        assert_eq!(table.get(metal_index), metal());
+    }
+
+    #[test]
+    fn saved_tables_load_unchanged() {
+        let path = temp_file("round_trip");
+        let table = MaterialTable::default();
+        table.index_of(metal());
+        table.save(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0..4], MATERIAL_MAGIC);
+
+        let loaded = MaterialTable::load(&path).unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(loaded.count(), 2);
+        assert_eq!(loaded.get(1), metal());
+        assert_eq!(loaded.index_of(metal()), 1);
+    }
+
+    #[test]
+    fn rejects_other_versions_and_missing_headers() {
+        let path = temp_file("invalid");
+        let list = bincode::serialize(&vec![VoxMaterial::default()]).unwrap();
+        for version in [0, MATERIAL_VERSION + 1] {
+            let mut bytes = MATERIAL_MAGIC.to_vec();
+            bytes.extend(version.to_le_bytes());
+            bytes.extend(list.iter());
+            fs::write(&path, bytes).unwrap();
+            assert!(MaterialTable::load(&path).is_err());
+        }
+        fs::write(&path, &list).unwrap();
+        assert!(MaterialTable::load(&path).is_err());
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,490 @@
+use std::{fmt::Write, fs, io, path::Path};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    player_controller::PCamera,
+    region::invalid_data,
+    streaming::FloatingOrigin,
+    vox_export::ExportSettings,
+    world_generator::{get_vox_color, StorageVoxel, VoxWorld, VoxelEntity},
+};
+
+/// Triangles with one material per face group, in voxel units.
+#[derive(Default)]
+pub struct GreedyMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub materials: Vec<StorageVoxel>,
+    /// Triangle indices for each material.
+    pub indices: Vec<Vec<u32>>,
+}
+
+/// `F10` exports the box around the camera as a mesh, `Shift+F10` the
+/// `VoxelEntity` closest to the camera. Like `export_keys` for `.vox`.
+pub fn mesh_export_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<ExportSettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    camera: Query<&GlobalTransform, With<PCamera>>,
+    entities: Query<&VoxelEntity>,
+) {
+    if !keys.just_pressed(KeyCode::F10) {
+        return;
+    }
+    let camera = match camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    if let Err(err) = fs::create_dir_all(&settings.dir) {
+        info!("Error creating {}: {}", settings.dir.display(), err);
+        return;
+    }
+
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let result = if shift {
+        let nearest = entities.iter().min_by(|a, b| {
+            let a = a
+                .transform
+                .translation
+                .distance_squared(camera.translation());
+            let b = b
+                .transform
+                .translation
+                .distance_squared(camera.translation());
+            a.total_cmp(&b)
+        });
+        let entity = match nearest {
+            Some(entity) => entity,
+            None => return,
+        };
+        let path = settings
+            .dir
+            .join(format!("{}.{}", entity.name, settings.mesh_format));
+        export_mesh_entity(entity, &path).map(|_| path)
+    } else {
+        let center = origin.to_world(camera.translation()).floor().as_ivec3();
+        let min = (center - settings.radius).to_array();
+        let max = (center + settings.radius).to_array();
+        let path = settings.dir.join(format!(
+            "region_{}_{}_{}.{}",
+            center.x, center.y, center.z, settings.mesh_format
+        ));
+        export_mesh_region(&world, min, max, &path).map(|_| path)
+    };
+    match result {
+        Ok(path) => info!("Exported {}", path.display()),
+        Err(err) => info!("Error exporting: {}", err),
+    }
+}
+
+/// Meshes the voxels of `VoxWorld` within `min` and `max` (inclusive) and
+/// writes them as `.obj` or `.gltf`, picked by the extension of `path`.
+pub fn export_mesh_region(
+    world: &VoxWorld,
+    min: [i32; 3],
+    max: [i32; 3],
+    path: &Path,
+) -> io::Result<()> {
+    write_mesh(&greedy_mesh(&world.voxels_in(min, max)), path)
+}
+
+/// Same for a `VoxelEntity`, relative to its transform.
+pub fn export_mesh_entity(entity: &VoxelEntity, path: &Path) -> io::Result<()> {
+    write_mesh(&greedy_mesh(&entity.voxels), path)
+}
+
+pub fn write_mesh(mesh: &GreedyMesh, path: &Path) -> io::Result<()> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("obj") => write_obj(mesh, path),
+        Some("gltf") => write_gltf(mesh, path),
+        _ => Err(invalid_data(&format!(
+            "{} is not an obj or gltf file",
+            path.display()
+        ))),
+    }
+}
+
+/// Merges the visible faces of equal voxels into as few rectangles as
+/// possible, one slice at a time for each of the six face directions.
+pub fn greedy_mesh(voxels: &[([i32; 3], StorageVoxel)]) -> GreedyMesh {
+    let mut mesh = GreedyMesh::default();
+    if voxels.is_empty() {
+        return mesh;
+    }
+
+    let mut grid: HashMap<[i32; 3], u32> = HashMap::new();
+    let mut min = voxels[0].0;
+    let mut max = voxels[0].0;
+    for (pos, vox) in voxels.iter() {
+        let material = match mesh.materials.iter().position(|m| m == vox) {
+            Some(i) => i,
+            None => {
+                mesh.materials.push(vox.clone());
+                mesh.indices.push(Vec::new());
+                mesh.materials.len() - 1
+            }
+        };
+        grid.insert(*pos, material as u32);
+        for i in 0..3 {
+            min[i] = min[i].min(pos[i]);
+            max[i] = max[i].max(pos[i]);
+        }
+    }
+
+    for d in 0..3 {
+        let (u, v) = ((d + 1) % 3, (d + 2) % 3);
+        let width = (max[u] - min[u] + 1) as usize;
+        let height = (max[v] - min[v] + 1) as usize;
+
+        for sign in [1, -1] {
+            let mut normal = [0.0; 3];
+            normal[d] = sign as f32;
+
+            for slice in min[d]..=max[d] {
+                // material of each visible face in this slice
+                let mut mask: Vec<Option<u32>> = vec![None; width * height];
+                for j in 0..height {
+                    for i in 0..width {
+                        let mut pos = [0; 3];
+                        pos[d] = slice;
+                        pos[u] = min[u] + i as i32;
+                        pos[v] = min[v] + j as i32;
+                        let mut next = pos;
+                        next[d] += sign;
+                        if let Some(material) = grid.get(&pos) {
+                            if !grid.contains_key(&next) {
+                                mask[j * width + i] = Some(*material);
+                            }
+                        }
+                    }
+                }
+
+                for j in 0..height {
+                    let mut i = 0;
+                    while i < width {
+                        let material = match mask[j * width + i] {
+                            Some(material) => material,
+                            None => {
+                                i += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut w = 1;
+                        while i + w < width && mask[j * width + i + w] == Some(material) {
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        'grow: while j + h < height {
+                            for k in 0..w {
+                                if mask[(j + h) * width + i + k] != Some(material) {
+                                    break 'grow;
+                                }
+                            }
+                            h += 1;
+                        }
+                        for l in 0..h {
+                            for k in 0..w {
+                                mask[(j + l) * width + i + k] = None;
+                            }
+                        }
+
+                        let plane = (slice + if sign > 0 { 1 } else { 0 }) as f32;
+                        let u0 = (min[u] + i as i32) as f32;
+                        let v0 = (min[v] + j as i32) as f32;
+                        let corners = [
+                            (u0, v0),
+                            (u0 + w as f32, v0),
+                            (u0 + w as f32, v0 + h as f32),
+                            (u0, v0 + h as f32),
+                        ];
+                        let base = mesh.positions.len() as u32;
+                        for (cu, cv) in corners {
+                            let mut p = [0.0; 3];
+                            p[d] = plane;
+                            p[u] = cu;
+                            p[v] = cv;
+                            mesh.positions.push(p);
+                            mesh.normals.push(normal);
+                        }
+                        // counter clockwise seen from the side the face points to
+                        let order = if sign > 0 {
+                            [0, 1, 2, 0, 2, 3]
+                        } else {
+                            [0, 2, 1, 0, 3, 2]
+                        };
+                        mesh.indices[material as usize].extend(order.map(|o| base + o));
+
+                        i += w;
+                    }
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+/// Writes an `.obj` with a `.mtl` next to it. Emissive voxels get a `Ke`
+/// colour scaled by their emission.
+pub fn write_obj(mesh: &GreedyMesh, path: &Path) -> io::Result<()> {
+    let mtl_path = path.with_extension("mtl");
+    let mtl_name = mtl_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("materials.mtl");
+
+    let mut mtl = String::new();
+    for (i, vox) in mesh.materials.iter().enumerate() {
+        let c = srgb_color(vox);
+        let _ = writeln!(mtl, "newmtl voxel_{}", i);
+        let _ = writeln!(mtl, "Kd {} {} {}", c[0], c[1], c[2]);
+        if vox.emission > 0.0 {
+            let _ = writeln!(
+                mtl,
+                "Ke {} {} {}",
+                c[0] * vox.emission,
+                c[1] * vox.emission,
+                c[2] * vox.emission
+            );
+        }
+        let _ = writeln!(mtl);
+    }
+    fs::write(&mtl_path, mtl)?;
+
+    let mut obj = String::new();
+    let _ = writeln!(obj, "mtllib {}", mtl_name);
+    for p in mesh.positions.iter() {
+        let _ = writeln!(obj, "v {} {} {}", p[0], p[1], p[2]);
+    }
+    for n in mesh.normals.iter() {
+        let _ = writeln!(obj, "vn {} {} {}", n[0], n[1], n[2]);
+    }
+    for (i, indices) in mesh.indices.iter().enumerate() {
+        let _ = writeln!(obj, "usemtl voxel_{}", i);
+        for face in indices.chunks_exact(3) {
+            let _ = writeln!(
+                obj,
+                "f {0}//{0} {1}//{1} {2}//{2}",
+                face[0] + 1,
+                face[1] + 1,
+                face[2] + 1
+            );
+        }
+    }
+    fs::write(path, obj)
+}
+
+/// Writes a `.gltf` with its buffer in a `.bin` next to it. Positions and
+/// normals are shared, each material has its own primitive. Emission above 1
+/// uses `KHR_materials_emissive_strength`.
+pub fn write_gltf(mesh: &GreedyMesh, path: &Path) -> io::Result<()> {
+    if mesh.positions.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nothing to export",
+        ));
+    }
+
+    let bin_path = path.with_extension("bin");
+    let bin_name = bin_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("mesh.bin");
+
+    let mut bin: Vec<u8> = Vec::new();
+    for p in mesh.positions.iter().chain(mesh.normals.iter()) {
+        for c in p {
+            bin.extend(c.to_le_bytes());
+        }
+    }
+    let vertex_bytes = mesh.positions.len() * 12;
+
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for p in mesh.positions.iter() {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    let mut buffer_views = vec![
+        format!(
+            r#"{{"buffer":0,"byteOffset":0,"byteLength":{},"target":34962}}"#,
+            vertex_bytes
+        ),
+        format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":34962}}"#,
+            vertex_bytes, vertex_bytes
+        ),
+    ];
+    let mut accessors = vec![
+        format!(
+            r#"{{"bufferView":0,"componentType":5126,"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+            mesh.positions.len(),
+            min[0],
+            min[1],
+            min[2],
+            max[0],
+            max[1],
+            max[2]
+        ),
+        format!(
+            r#"{{"bufferView":1,"componentType":5126,"count":{},"type":"VEC3"}}"#,
+            mesh.normals.len()
+        ),
+    ];
+    let mut primitives = Vec::new();
+    let mut materials = Vec::new();
+    let mut emissive_strength = false;
+
+    for (i, (vox, indices)) in mesh.materials.iter().zip(mesh.indices.iter()).enumerate() {
+        let c = srgb_color(vox).map(srgb_to_linear);
+        let mut material = format!(
+            r#"{{"name":"voxel_{}","pbrMetallicRoughness":{{"baseColorFactor":[{},{},{},1.0],"metallicFactor":0.0,"roughnessFactor":1.0}}"#,
+            i, c[0], c[1], c[2]
+        );
+        if vox.emission > 0.0 {
+            let _ = write!(
+                material,
+                r#","emissiveFactor":[{},{},{}]"#,
+                c[0], c[1], c[2]
+            );
+            if vox.emission > 1.0 {
+                emissive_strength = true;
+                let _ = write!(
+                    material,
+                    r#","extensions":{{"KHR_materials_emissive_strength":{{"emissiveStrength":{}}}}}"#,
+                    vox.emission
+                );
+            }
+        }
+        material.push('}');
+        materials.push(material);
+
+        // a material can end up with every face hidden
+        if indices.is_empty() {
+            continue;
+        }
+        let offset = bin.len();
+        for index in indices.iter() {
+            bin.extend(index.to_le_bytes());
+        }
+        buffer_views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":34963}}"#,
+            offset,
+            indices.len() * 4
+        ));
+        accessors.push(format!(
+            r#"{{"bufferView":{},"componentType":5125,"count":{},"type":"SCALAR"}}"#,
+            buffer_views.len() - 1,
+            indices.len()
+        ));
+        primitives.push(format!(
+            r#"{{"attributes":{{"POSITION":0,"NORMAL":1}},"indices":{},"material":{}}}"#,
+            accessors.len() - 1,
+            i
+        ));
+    }
+
+    let extensions = if emissive_strength {
+        r#""extensionsUsed":["KHR_materials_emissive_strength"],"#
+    } else {
+        ""
+    };
+    let json = format!(
+        r#"{{"asset":{{"version":"2.0","generator":"light_physics_raymarcher"}},{}"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"meshes":[{{"primitives":[{}]}}],"materials":[{}],"buffers":[{{"uri":"{}","byteLength":{}}}],"bufferViews":[{}],"accessors":[{}]}}"#,
+        extensions,
+        primitives.join(","),
+        materials.join(","),
+        bin_name,
+        bin.len(),
+        buffer_views.join(","),
+        accessors.join(",")
+    );
+
+    fs::write(&bin_path, &bin)?;
+    fs::write(path, json)
+}
+
+fn srgb_color(vox: &StorageVoxel) -> [f32; 3] {
+    let c = get_vox_color(vox.color);
+    [c.r, c.g, c.b].map(|c| c as f32 / 255.0)
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    Color::srgb(c, 0.0, 0.0).to_linear().red
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world_generator::color_voxel;
+
+    fn voxel(r: u8, g: u8, b: u8, emission: f32) -> StorageVoxel {
+        color_voxel(dot_vox::Color { r, g, b, a: 255 }, emission)
+    }
+
+    fn solid_box(size: i32, vox: &StorageVoxel) -> Vec<([i32; 3], StorageVoxel)> {
+        let mut voxels = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    voxels.push(([x, y, z], vox.clone()));
+                }
+            }
+        }
+        voxels
+    }
+
+    fn quads(mesh: &GreedyMesh) -> usize {
+        mesh.indices.iter().map(|i| i.len()).sum::<usize>() / 6
+    }
+
+    #[test]
+    fn solid_box_is_six_quads() {
+        let mesh = greedy_mesh(&solid_box(4, &voxel(200, 100, 50, 0.0)));
+        assert_eq!(quads(&mesh), 6);
+        assert_eq!(mesh.positions.len(), 24);
+        assert_eq!(mesh.materials.len(), 1);
+
+        for (p, n) in mesh.positions.iter().zip(mesh.normals.iter()) {
+            // every corner lies on the outside of the box, on the side the
+            // face points to
+            for i in 0..3 {
+                assert!(p[i] == 0.0 || p[i] == 4.0);
+                if n[i] != 0.0 {
+                    assert_eq!(p[i], if n[i] > 0.0 { 4.0 } else { 0.0 });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn different_voxels_are_not_merged() {
+        let mut voxels = solid_box(2, &voxel(200, 100, 50, 0.0));
+        voxels[0].1 = voxel(10, 10, 10, 1.0);
+        let mesh = greedy_mesh(&voxels);
+        assert_eq!(mesh.materials.len(), 2);
+        // the odd voxel shows three faces, which split each of the three
+        // faces of the box it touches into an L of two rectangles
+        assert_eq!(mesh.indices[0].len() / 6, 3);
+        assert_eq!(mesh.indices[1].len() / 6, 3 * 2 + 3);
+    }
+
+    #[test]
+    fn obj_has_two_triangles_per_quad_and_emissive_materials() {
+        let mesh = greedy_mesh(&solid_box(2, &voxel(255, 0, 0, 2.0)));
+        let path = std::env::temp_dir().join(format!("greedy_{}.obj", std::process::id()));
+        write_mesh(&mesh, &path).unwrap();
+        let obj = fs::read_to_string(&path).unwrap();
+        let mtl = fs::read_to_string(path.with_extension("mtl")).unwrap();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("mtl"));
+
+        assert_eq!(obj.lines().filter(|l| l.starts_with("f ")).count(), 12);
+        assert!(mtl.contains("Ke 2 0 0"));
+    }
+}
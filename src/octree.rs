@@ -44,6 +44,8 @@ pub struct OctreeVoxel {
     pub light_color: Vec3,
     pub lit: u32,
     pub id: u32,
+    /// Index into the material buffer.
+    pub material: u32,
 }
 impl OctreeVoxel {
     pub fn empty() -> Self {
@@ -53,6 +55,7 @@ impl OctreeVoxel {
             light_color: Vec3::ZERO,
             lit: 0,
             id: 0,
+            material: 0,
         }
     }
 }
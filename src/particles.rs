@@ -0,0 +1,265 @@
+use bevy::{math::Affine3A, prelude::*, utils::HashSet};
+
+use crate::{
+    streaming::FloatingOrigin,
+    world_generator::{StorageVoxel, VoxWorld, VoxelEntity},
+};
+
+/// A short-lived voxel, drawn as a single voxel in the octree like the
+/// voxels of entities. Positions are local to the floating origin.
+#[derive(Clone, Debug)]
+pub struct Particle {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    /// 8-bit sRGB like `StorageVoxel::color`.
+    pub color: [u8; 3],
+    pub emission: f32,
+    pub age: f32,
+    pub lifetime: f32,
+    /// Multiplies `ParticleSettings::gravity`, negative rises like smoke.
+    pub gravity: f32,
+    /// Fraction of the velocity lost per second.
+    pub drag: f32,
+}
+impl Particle {
+    /// The voxel drawn for the particle, its glow fades over its lifetime.
+    pub fn voxel(self: &Self) -> StorageVoxel {
+        StorageVoxel {
+            id: 1,
+            color: self.color,
+            emission: self.emission * (1.0 - self.age / self.lifetime).max(0.0),
+            material: 0,
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct ParticleSettings {
+    pub enabled: bool,
+    /// New particles are dropped once there are this many.
+    pub max_particles: usize,
+    pub gravity: Vec3,
+}
+impl Default for ParticleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_particles: 4096,
+            gravity: Vec3::new(0.0, -30.0, 0.0),
+        }
+    }
+}
+
+/// Every live particle, simulated on the CPU and copied into each octree
+/// build.
+#[derive(Resource, Default, Clone)]
+pub struct Particles {
+    pub particles: Vec<Particle>,
+}
+
+/// Emits particles from the entity it is attached to. A `VoxelEntity` emits
+/// from its transform, anything else from its `GlobalTransform`.
+#[derive(Component, Clone)]
+pub struct ParticleEmitter {
+    /// Particles per second.
+    pub rate: f32,
+    /// Emitted particles start as a copy of this, with `velocity` spread out.
+    pub template: Particle,
+    /// Random velocity added on top of the template's, per axis.
+    pub spread: Vec3,
+    /// Where particles appear relative to the entity, before rotation.
+    pub offset: Vec3,
+    /// Removes the emitter after this many seconds, `None` emits forever.
+    pub duration: Option<f32>,
+    pub age: f32,
+    pending: f32,
+}
+impl ParticleEmitter {
+    pub fn new(template: Particle, rate: f32) -> Self {
+        ParticleEmitter {
+            rate,
+            template,
+            spread: Vec3::ZERO,
+            offset: Vec3::ZERO,
+            duration: None,
+            age: 0.0,
+            pending: 0.0,
+        }
+    }
+
+    pub fn sparks() -> Self {
+        ParticleEmitter {
+            spread: Vec3::splat(20.0),
+            ..ParticleEmitter::new(
+                Particle {
+                    position: Vec3::ZERO,
+                    velocity: Vec3::new(0.0, 10.0, 0.0),
+                    color: [255, 180, 60],
+                    emission: 1.0,
+                    age: 0.0,
+                    lifetime: 0.6,
+                    gravity: 1.0,
+                    drag: 0.5,
+                },
+                60.0,
+            )
+        }
+    }
+
+    pub fn smoke() -> Self {
+        ParticleEmitter {
+            spread: Vec3::new(2.0, 1.0, 2.0),
+            ..ParticleEmitter::new(
+                Particle {
+                    position: Vec3::ZERO,
+                    velocity: Vec3::new(0.0, 4.0, 0.0),
+                    color: [70, 70, 70],
+                    emission: 0.0,
+                    age: 0.0,
+                    lifetime: 3.0,
+                    gravity: -0.05,
+                    drag: 0.8,
+                },
+                10.0,
+            )
+        }
+    }
+}
+
+impl Particles {
+    pub fn spawn(self: &mut Self, settings: &ParticleSettings, particle: Particle) {
+        if self.particles.len() < settings.max_particles {
+            self.particles.push(particle);
+        }
+    }
+
+    /// Spawns `count` copies of `template` at `position` with random velocity
+    /// up to `spread` on each axis added.
+    pub fn burst(
+        self: &mut Self,
+        settings: &ParticleSettings,
+        template: &Particle,
+        position: Vec3,
+        spread: Vec3,
+        count: usize,
+    ) {
+        for _ in 0..count {
+            self.spawn(
+                settings,
+                Particle {
+                    position,
+                    velocity: template.velocity + random_offset() * spread,
+                    lifetime: template.lifetime * (0.5 + rand::random::<f32>()),
+                    ..template.clone()
+                },
+            );
+        }
+    }
+}
+
+/// A random vector with every axis in -1..1.
+fn random_offset() -> Vec3 {
+    Vec3::new(
+        rand::random::<f32>() * 2.0 - 1.0,
+        rand::random::<f32>() * 2.0 - 1.0,
+        rand::random::<f32>() * 2.0 - 1.0,
+    )
+}
+
+pub fn emit_particles(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<ParticleSettings>,
+    mut particles: ResMut<Particles>,
+    mut emitters: Query<(
+        Entity,
+        &mut ParticleEmitter,
+        Option<&VoxelEntity>,
+        Option<&GlobalTransform>,
+    )>,
+) {
+    if !settings.enabled {
+        return;
+    }
+    let dt = time.delta_seconds();
+    for (entity, mut emitter, vox_entity, global) in emitters.iter_mut() {
+        emitter.age += dt;
+        if emitter.duration.map_or(false, |d| emitter.age > d) {
+            commands.entity(entity).remove::<ParticleEmitter>();
+            continue;
+        }
+        let position = match (vox_entity, global) {
+            (Some(vox_entity), _) => vox_entity.transform.transform_point(emitter.offset),
+            (None, Some(global)) => global.transform_point(emitter.offset),
+            (None, None) => continue,
+        };
+
+        emitter.pending += emitter.rate * dt;
+        let count = emitter.pending as usize;
+        emitter.pending -= count as f32;
+        particles.burst(
+            &settings,
+            &emitter.template,
+            position,
+            emitter.spread,
+            count,
+        );
+    }
+}
+
+/// Moves particles and removes the ones that are too old or flew into a
+/// solid voxel of the world or of a `VoxelEntity`.
+pub fn simulate_particles(
+    time: Res<Time>,
+    settings: Res<ParticleSettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    mut particles: ResMut<Particles>,
+    entities: Query<&VoxelEntity>,
+) {
+    if !settings.enabled {
+        particles.particles.clear();
+        return;
+    }
+    let dt = time.delta_seconds();
+    let chunks = world.world.read().unwrap();
+    // maps local positions into each entity, with its bounds to skip most
+    // of them without a lookup
+    let solids: Vec<(Affine3A, IVec3, IVec3, HashSet<IVec3>)> = entities
+        .iter()
+        .filter(|entity| !entity.voxels.is_empty())
+        .map(|entity| {
+            let cells: HashSet<IVec3> = entity
+                .voxels
+                .iter()
+                .map(|(pos, _)| IVec3::from_array(*pos))
+                .collect();
+            let min = cells.iter().fold(IVec3::MAX, |a, b| a.min(*b));
+            let max = cells.iter().fold(IVec3::MIN, |a, b| a.max(*b));
+            (entity.transform.compute_affine().inverse(), min, max, cells)
+        })
+        .collect();
+
+    particles.particles.retain_mut(|particle| {
+        particle.age += dt;
+        if particle.age >= particle.lifetime {
+            return false;
+        }
+        particle.velocity += settings.gravity * particle.gravity * dt;
+        particle.velocity *= (1.0 - particle.drag * dt).max(0.0);
+        particle.position += particle.velocity * dt;
+
+        let world_pos = origin.to_world(particle.position).floor();
+        let cell = [world_pos.x as i32, world_pos.y as i32, world_pos.z as i32];
+        if chunks.get_voxel(cell).is_some() {
+            return false;
+        }
+        !solids.iter().any(|(inverse, min, max, cells)| {
+            let cell = inverse
+                .transform_point3(particle.position)
+                .floor()
+                .as_ivec3();
+            cell.cmpge(*min).all() && cell.cmple(*max).all() && cells.contains(&cell)
+        })
+    });
+}
@@ -7,8 +7,10 @@ use bevy::{
 
 use crate::{
     compute::RayTracerTexture,
+    fluid::fluid_of,
     pre_compute::{FOV, RESHIGHT, RESWIDTH},
-    world_generator::VoxWorld,
+    streaming::{ChunkStreamer, FloatingOrigin},
+    world_generator::{chunk_of, ChunkMap, VoxWorld},
 };
 
 #[derive(Component)]
@@ -24,16 +26,43 @@ pub struct PSprite;
 pub struct MovementSettings {
     pub sensitivity: f32,
     pub freecam_speed: f32,
+    /// Everything below is for walk mode, in voxels and seconds.
+    pub walk_speed: f32,
+    pub jump_speed: f32,
+    pub gravity: f32,
+    /// Size of the player's box, the camera sits `eye_height` above its
+    /// bottom.
+    pub player_width: f32,
+    pub player_height: f32,
+    pub eye_height: f32,
+    /// Ledges up to this high are stepped onto without jumping.
+    pub step_height: f32,
 }
 impl Default for MovementSettings {
     fn default() -> Self {
         Self {
             sensitivity: 0.00008,
             freecam_speed: 150.0,
+            walk_speed: 40.0,
+            jump_speed: 28.0,
+            gravity: 80.0,
+            player_width: 6.0,
+            player_height: 18.0,
+            eye_height: 16.0,
+            step_height: 1.0,
         }
     }
 }
 
+/// Walk mode, toggled with `F`. The fly-cam ignores the world, walking
+/// falls, jumps and collides with it.
+#[derive(Resource, Default)]
+pub struct WalkState {
+    pub walking: bool,
+    pub velocity: Vec3,
+    pub grounded: bool,
+}
+
 #[derive(Resource, Default)]
 pub struct InputState {
     reader_motion: ManualEventReader<MouseMotion>,
@@ -45,12 +74,11 @@ pub fn spawn_player(
     mut commands: Commands,
     render_texture: Res<RayTracerTexture>,
     vox_world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
 ) {
     let player = (
-        SpatialBundle::from_transform(Transform::from_xyz(
-            vox_world.root[0] as f32,
-            vox_world.root[1] as f32,
-            vox_world.root[2] as f32 + 32.0,
+        SpatialBundle::from_transform(Transform::from_translation(
+            origin.to_local(vox_world.root) + Vec3::new(0.0, 0.0, 32.0),
         )),
         Player,
     );
@@ -118,12 +146,25 @@ fn toggle_grab_cursor(window: &mut Window) {
     }
 }
 
+pub fn toggle_walk_mode(keys: Res<ButtonInput<KeyCode>>, mut walk: ResMut<WalkState>) {
+    if keys.just_pressed(KeyCode::KeyF) {
+        walk.walking = !walk.walking;
+        walk.velocity = Vec3::ZERO;
+        walk.grounded = false;
+        info!("{} mode", if walk.walking { "walk" } else { "fly" });
+    }
+}
+
 pub fn move_player(
     keys: Res<ButtonInput<KeyCode>>,
     mut player_query: Query<&mut Transform, With<Player>>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
     settings: Res<MovementSettings>,
     time: Res<Time>,
+    mut walk: ResMut<WalkState>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    streamer: Res<ChunkStreamer>,
 ) {
     if let Ok(window) = primary_window.get_single() {
         for mut transform in player_query.iter_mut() {
@@ -131,6 +172,7 @@ pub fn move_player(
             let local_z = transform.local_z();
             let forward = -Vec3::new(local_z.x, 0., local_z.z);
             let right = Vec3::new(local_z.z, 0., -local_z.x);
+            let mut jump = false;
 
             for key in keys.get_pressed() {
                 match window.cursor.grab_mode {
@@ -140,13 +182,59 @@ pub fn move_player(
                         KeyCode::KeyS => velocity -= forward,
                         KeyCode::KeyA => velocity -= right,
                         KeyCode::KeyD => velocity += right,
-                        KeyCode::Space => velocity += Vec3::Y,
+                        KeyCode::Space => {
+                            velocity += Vec3::Y;
+                            jump = true;
+                        }
                         KeyCode::ControlLeft => velocity -= Vec3::Y,
                         _ => (),
                     },
                 }
             }
 
+            if walk.walking {
+                // wait for everything around the player to arrive before
+                // falling onto it or walking into it
+                let half = settings.player_width / 2.0;
+                let lo = origin
+                    .to_world(transform.translation - Vec3::new(half, settings.eye_height, half))
+                    .floor();
+                let hi = origin
+                    .to_world(
+                        transform.translation
+                            + Vec3::new(half, settings.player_height - settings.eye_height, half),
+                    )
+                    .floor();
+                let min = chunk_of([lo.x as i32, lo.y as i32, lo.z as i32]).0;
+                let max = chunk_of([hi.x as i32, hi.y as i32, hi.z as i32]).0;
+                let loaded = (min[0]..=max[0]).all(|x| {
+                    (min[1]..=max[1])
+                        .all(|y| (min[2]..=max[2]).all(|z| streamer.has_arrived([x, y, z])))
+                });
+                if !loaded {
+                    continue;
+                }
+
+                let chunks = world.world.read().unwrap();
+                let body = PlayerBox {
+                    chunks: &chunks,
+                    origin: &origin,
+                    half_width: settings.player_width / 2.0,
+                    height: settings.player_height,
+                };
+                let wish = Vec3::new(velocity.x, 0.0, velocity.z).normalize_or_zero();
+                walk_player(
+                    &mut transform,
+                    &mut walk,
+                    &settings,
+                    &body,
+                    wish * settings.walk_speed,
+                    jump,
+                    time.delta_seconds().min(0.1),
+                );
+                continue;
+            }
+
             velocity = velocity.normalize_or_zero();
 
             transform.translation.x += velocity.x * time.delta_seconds() * settings.freecam_speed;
@@ -156,6 +244,122 @@ pub fn move_player(
     }
 }
 
+/// Moves the player's box one axis at a time, so it slides along walls. A
+/// grounded player blocked sideways tries again `step_height` higher to walk
+/// up ledges.
+fn walk_player(
+    transform: &mut Transform,
+    walk: &mut WalkState,
+    settings: &MovementSettings,
+    body: &PlayerBox,
+    wish: Vec3,
+    jump: bool,
+    dt: f32,
+) {
+    let mut feet = transform.translation - Vec3::Y * settings.eye_height;
+    // climb out of anything built into the player, or turned into walk mode
+    // in. Only loaded chunks hold voxels, so there is always room further up.
+    while body.collides(feet) {
+        feet.y += 1.0;
+    }
+
+    walk.velocity.x = wish.x;
+    walk.velocity.z = wish.z;
+    if jump && walk.grounded {
+        walk.velocity.y = settings.jump_speed;
+    }
+    walk.velocity.y -= settings.gravity * dt;
+
+    for axis in [0, 2] {
+        let delta = walk.velocity[axis] * dt;
+        let start = feet[axis];
+        if !body.move_axis(&mut feet, axis, delta) || !walk.grounded {
+            continue;
+        }
+        let raised = feet + Vec3::Y * settings.step_height;
+        if body.collides(raised) {
+            continue;
+        }
+        // only the part of the move the wall stopped
+        let mut stepped = raised;
+        if !body.move_axis(&mut stepped, axis, delta - (feet[axis] - start)) {
+            feet = stepped;
+        }
+    }
+
+    let falling = walk.velocity.y <= 0.0;
+    let hit = body.move_axis(&mut feet, 1, walk.velocity.y * dt);
+    walk.grounded = hit && falling;
+    if hit {
+        walk.velocity.y = 0.0;
+    }
+
+    transform.translation = feet + Vec3::Y * settings.eye_height;
+}
+
+/// The player's box against the world, positioned by the centre of its
+/// bottom face in local coordinates.
+struct PlayerBox<'a> {
+    chunks: &'a ChunkMap,
+    origin: &'a FloatingOrigin,
+    half_width: f32,
+    height: f32,
+}
+impl<'a> PlayerBox<'a> {
+    fn bounds(self: &Self) -> (Vec3, Vec3) {
+        (
+            Vec3::new(-self.half_width, 0.0, -self.half_width),
+            Vec3::new(self.half_width, self.height, self.half_width),
+        )
+    }
+
+    /// Whether any solid voxel overlaps the box. Fluids can be walked through.
+    fn collides(self: &Self, feet: Vec3) -> bool {
+        let (lo, hi) = self.bounds();
+        let min = self.origin.to_world(feet + lo).floor();
+        let max = self.origin.to_world(feet + hi).ceil();
+        for x in min.x as i32..max.x as i32 {
+            for y in min.y as i32..max.y as i32 {
+                for z in min.z as i32..max.z as i32 {
+                    if let Some(vox) = self.chunks.get_voxel([x, y, z]) {
+                        if fluid_of(vox).is_none() {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Moves along one axis in steps smaller than a voxel. On a hit the box
+    /// is placed flush against the voxel face and true is returned.
+    fn move_axis(self: &Self, feet: &mut Vec3, axis: usize, delta: f32) -> bool {
+        let steps = (delta.abs() / 0.5).ceil() as u32;
+        let (lo, hi) = self.bounds();
+        for _ in 0..steps {
+            let mut next = *feet;
+            next[axis] += delta / steps as f32;
+            if !self.collides(next) {
+                *feet = next;
+                continue;
+            }
+            // the origin is a whole voxel, so the grid lines up in local space
+            let edge = if delta > 0.0 {
+                (next[axis] + hi[axis]).floor() - hi[axis] - 0.001
+            } else {
+                (next[axis] + lo[axis]).floor() + 1.0 - lo[axis] + 0.001
+            };
+            next[axis] = edge;
+            if !self.collides(next) {
+                *feet = next;
+            }
+            return true;
+        }
+        false
+    }
+}
+
 pub fn player_look(
     settings: Res<MovementSettings>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
@@ -58,6 +58,13 @@ fn create_storage_texture((x, y): (i64, i64)) -> Image {
         | wgpu::TextureUsages::COPY_SRC
         | wgpu::TextureUsages::STORAGE_BINDING
         | wgpu::TextureUsages::TEXTURE_BINDING;
+    // the pathtracer writes sRGB into the storage texture, sampling it through
+    // an sRGB view turns it back into linear for the sprite
+    image.texture_descriptor.view_formats = &[wgpu::TextureFormat::Rgba8UnormSrgb];
+    image.texture_view_descriptor = Some(wgpu::TextureViewDescriptor {
+        format: Some(wgpu::TextureFormat::Rgba8UnormSrgb),
+        ..Default::default()
+    });
 
     image
 }
@@ -0,0 +1,241 @@
+use std::{fs, io, path::Path};
+
+use bevy::prelude::*;
+
+use crate::{
+    region::invalid_data,
+    world_generator::{color_voxel, insert_voxel_list, StorageVoxel, VoxelEntity, WorldData},
+};
+
+const CODE_FLAG: u32 = 2;
+const NEXT_SLICE_FLAG: u32 = 6;
+
+/// One named matrix of a Qubicle `.qb` file, voxels relative to `position`.
+#[derive(Clone)]
+pub struct QbMatrix {
+    pub name: String,
+    pub position: [i32; 3],
+    pub voxels: Vec<([i32; 3], StorageVoxel)>,
+}
+impl QbMatrix {
+    pub fn into_entity(self: Self, offset: [i32; 3]) -> VoxelEntity {
+        VoxelEntity {
+            name: self.name,
+            transform: Transform::from_xyz(
+                (self.position[0] + offset[0]) as f32,
+                (self.position[1] + offset[1]) as f32,
+                (self.position[2] + offset[2]) as f32,
+            ),
+            voxels: self.voxels,
+        }
+    }
+}
+
+/// Reads all matrices of a `.qb` file. Qubicle is Y-up like the world, files
+/// with a left-handed z axis are mirrored along z.
+pub fn load_qb(path: &Path) -> io::Result<Vec<QbMatrix>> {
+    let bytes = fs::read(path)?;
+    let mut reader = Reader {
+        bytes: &bytes,
+        at: 0,
+    };
+
+    let _version = reader.u32()?;
+    let bgra = reader.u32()? == 1;
+    let left_handed = reader.u32()? == 0;
+    let compressed = reader.u32()? == 1;
+    let _visibility_mask = reader.u32()?;
+    let count = reader.u32()?;
+
+    let mut matrices = Vec::new();
+    for _ in 0..count {
+        let name_len = reader.u8()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(name_len)?).into_owned();
+        let size = [reader.u32()?, reader.u32()?, reader.u32()?];
+        let position = [reader.i32()?, reader.i32()?, reader.i32()?];
+        if size.iter().any(|s| *s > 1024) {
+            return Err(invalid_data(&format!("matrix {} is too large", name)));
+        }
+
+        let mut voxels = Vec::new();
+        let mut place = |x: u32, y: u32, z: u32, data: u32| {
+            if let Some(vox) = qb_voxel(data, bgra) {
+                let z = if left_handed { size[2] - 1 - z } else { z };
+                voxels.push(([x as i32, y as i32, z as i32], vox));
+            }
+        };
+
+        if compressed {
+            for z in 0..size[2] {
+                let mut index = 0;
+                loop {
+                    let data = reader.u32()?;
+                    if data == NEXT_SLICE_FLAG {
+                        break;
+                    }
+                    let (repeat, data) = match data {
+                        CODE_FLAG => (reader.u32()?, reader.u32()?),
+                        data => (1, data),
+                    };
+                    for _ in 0..repeat {
+                        if index >= size[0] * size[1] {
+                            return Err(invalid_data("run past the end of a slice"));
+                        }
+                        place(index % size[0], index / size[0], z, data);
+                        index += 1;
+                    }
+                }
+            }
+        } else {
+            for z in 0..size[2] {
+                for y in 0..size[1] {
+                    for x in 0..size[0] {
+                        let data = reader.u32()?;
+                        place(x, y, z, data);
+                    }
+                }
+            }
+        }
+
+        matrices.push(QbMatrix {
+            name,
+            position,
+            voxels,
+        });
+    }
+
+    Ok(matrices)
+}
+
+/// Puts every matrix of a `.qb` file into the world at `offset`.
+pub fn insert_qb(matrices: &[QbMatrix], offset: [i32; 3], world: &mut WorldData) {
+    for matrix in matrices.iter() {
+        let offset = [
+            matrix.position[0] + offset[0],
+            matrix.position[1] + offset[1],
+            matrix.position[2] + offset[2],
+        ];
+        insert_voxel_list(&matrix.voxels, offset, world);
+    }
+}
+
+/// Alpha 0 is empty, with a visibility mask any other value is solid.
+fn qb_voxel(data: u32, bgra: bool) -> Option<StorageVoxel> {
+    let [a, b, c, alpha] = data.to_le_bytes();
+    if alpha == 0 {
+        return None;
+    }
+    let (r, g, b) = if bgra { (c, b, a) } else { (a, b, c) };
+    Some(color_voxel(dot_vox::Color { r, g, b, a: 255 }, 0.0))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+impl<'a> Reader<'a> {
+    fn bytes(self: &mut Self, len: usize) -> io::Result<&'a [u8]> {
+        if self.at + len > self.bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "qb file ends early",
+            ));
+        }
+        let bytes = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(self: &mut Self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(self: &mut Self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(self: &mut Self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn colors(matrix: &QbMatrix) -> Vec<([i32; 3], [u8; 3])> {
+        let mut voxels: Vec<([i32; 3], [u8; 3])> = matrix
+            .voxels
+            .iter()
+            .map(|(pos, vox)| (*pos, vox.color))
+            .collect();
+        voxels.sort();
+        voxels
+    }
+
+    #[test]
+    fn reads_uncompressed_matrices() {
+        let matrices = load_qb(&fixture("model.qb")).unwrap();
+        assert_eq!(matrices.len(), 1);
+        assert_eq!(matrices[0].name, "body");
+        assert_eq!(matrices[0].position, [5, 6, 7]);
+        assert_eq!(
+            colors(&matrices[0]),
+            vec![([0, 0, 0], [255, 0, 0]), ([1, 0, 1], [0, 255, 0])]
+        );
+    }
+
+    #[test]
+    fn reads_run_length_encoded_bgra_left_handed_matrices() {
+        let matrices = load_qb(&fixture("model_rle.qb")).unwrap();
+        assert_eq!(matrices.len(), 1);
+        // the first slice is mirrored to the back
+        assert_eq!(
+            colors(&matrices[0]),
+            vec![
+                ([0, 0, 0], [255, 0, 0]),
+                ([0, 0, 1], [0, 0, 255]),
+                ([1, 0, 1], [0, 0, 255]),
+                ([2, 0, 1], [0, 0, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn inserts_matrices_at_their_position() {
+        let matrices = load_qb(&fixture("model.qb")).unwrap();
+        let mut world = WorldData::default();
+        insert_qb(&matrices, [100, 0, 0], &mut world);
+        assert_eq!(
+            world.data.get_voxel([105, 6, 7]).map(|v| v.color),
+            Some([255, 0, 0])
+        );
+        assert_eq!(
+            world.data.get_voxel([106, 6, 8]).map(|v| v.color),
+            Some([0, 255, 0])
+        );
+        assert!(world.data.get_voxel([106, 6, 7]).is_none());
+
+        let entity = matrices[0].clone().into_entity([100, 0, 0]);
+        assert_eq!(entity.transform.translation, Vec3::new(105.0, 6.0, 7.0));
+        assert_eq!(entity.voxels.len(), 2);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = fs::read(fixture("model_rle.qb")).unwrap();
+        let path = std::env::temp_dir().join(format!("truncated_{}.qb", std::process::id()));
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(load_qb(&path).is_err());
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use crossbeam_channel::{unbounded, Sender};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{
+    chunk::Chunk,
+    material::MaterialTable,
+    world_generator::{StorageVoxel, VoxWorld},
+};
+
+pub const REGION_SIZE: u32 = 8;
+pub const REGION_VERSION: u32 = 1;
+pub const SAVE_DIR: &str = "Assets/worlds/castle";
+pub const SAVE_INTERVAL: f32 = 10.0;
+pub const MATERIAL_FILE: &str = "materials.bin";
+
+const REGION_MAGIC: [u8; 4] = *b"VXRG";
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const HEADER_LEN: usize = 8 + REGION_CHUNKS * 8;
+
+/// One region file: a header with an (offset, length) entry per chunk slot,
+/// followed by the zlib compressed chunk blobs. A length of 0 means the chunk
+/// was never saved.
+#[derive(Clone)]
+pub struct Region {
+    blobs: Vec<Option<Vec<u8>>>,
+}
+impl Region {
+    pub fn empty() -> Self {
+        Region {
+            blobs: vec![None; REGION_CHUNKS],
+        }
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < HEADER_LEN || bytes[0..4] != REGION_MAGIC {
+            return Err(invalid_data("not a region file"));
+        }
+
+        let version = read_u32(&bytes, 4);
+        if version != REGION_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported region version {} (expected {})",
+                version, REGION_VERSION
+            )));
+        }
+
+        let mut region = Region::empty();
+        for slot in 0..REGION_CHUNKS {
+            let offset = read_u32(&bytes, 8 + slot * 8) as usize;
+            let length = read_u32(&bytes, 12 + slot * 8) as usize;
+            if length == 0 {
+                continue;
+            }
+            if offset < HEADER_LEN || offset + length > bytes.len() {
+                return Err(invalid_data("chunk blob out of range"));
+            }
+            region.blobs[slot] = Some(bytes[offset..offset + length].to_vec());
+        }
+
+        Ok(region)
+    }
+
+    /// Writes to a temporary file first so a crash mid save never leaves a
+    /// half written region behind.
+    pub fn write(self: &Self, path: &Path) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        let mut body = Vec::new();
+        header.extend_from_slice(&REGION_MAGIC);
+        header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+        for blob in self.blobs.iter() {
+            match blob {
+                Some(blob) => {
+                    let offset = (HEADER_LEN + body.len()) as u32;
+                    header.extend_from_slice(&offset.to_le_bytes());
+                    header.extend_from_slice(&(blob.len() as u32).to_le_bytes());
+                    body.extend_from_slice(blob);
+                }
+                None => header.extend_from_slice(&[0; 8]),
+            }
+        }
+
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&header)?;
+        file.write_all(&body)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn chunk(self: &Self, local: [u32; 3]) -> io::Result<Option<Chunk>> {
+        match &self.blobs[slot_index(local)] {
+            Some(blob) => decode_chunk(blob).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_chunk(self: &mut Self, local: [u32; 3], chunk: &Chunk) -> io::Result<()> {
+        self.blobs[slot_index(local)] = if chunk.is_empty() {
+            None
+        } else {
+            Some(encode_chunk(chunk)?)
+        };
+        Ok(())
+    }
+}
+
+/// Chunks are stored as a palette plus run length encoded palette indices,
+/// see `Chunk::to_runs`. Saving the same chunk twice produces the same bytes.
+pub fn encode_chunk(chunk: &Chunk) -> io::Result<Vec<u8>> {
+    let raw = bincode::serialize(&chunk.to_runs()).map_err(|err| invalid_data(&err.to_string()))?;
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&raw)?;
+    encoder.finish()
+}
+
+pub fn decode_chunk(blob: &[u8]) -> io::Result<Chunk> {
+    let mut raw = Vec::new();
+    ZlibDecoder::new(blob).read_to_end(&mut raw)?;
+
+    let (palette, runs): (Vec<StorageVoxel>, Vec<(u32, u32)>) =
+        bincode::deserialize(&raw).map_err(|err| invalid_data(&err.to_string()))?;
+    Chunk::from_runs(&palette, &runs).ok_or_else(|| invalid_data("corrupt chunk runs"))
+}
+
+/// Directory of region files, with every region that has been touched kept
+/// in memory so streaming and saving do not re-read the same file. The
+/// material table the saved voxels refer to is kept next to them.
+#[derive(Resource, Clone)]
+pub struct RegionStore {
+    pub dir: PathBuf,
+    pub materials: MaterialTable,
+    /// False when the save directory could not be opened. Saved chunks are
+    /// then only kept in memory.
+    pub persistent: bool,
+    regions: Arc<Mutex<HashMap<[i32; 3], Region>>>,
+}
+impl RegionStore {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let materials = MaterialTable::load(&dir.join(MATERIAL_FILE))?;
+        Ok(RegionStore {
+            dir,
+            materials,
+            persistent: true,
+            regions: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// A store that never touches the disk.
+    pub fn in_memory() -> Self {
+        RegionStore {
+            dir: PathBuf::new(),
+            materials: MaterialTable::default(),
+            persistent: false,
+            regions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn has_saved_world(self: &Self) -> bool {
+        if !self.persistent {
+            return false;
+        }
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .flatten()
+                .any(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("vxr")),
+            Err(_) => false,
+        }
+    }
+
+    pub fn region_path(self: &Self, region: [i32; 3]) -> PathBuf {
+        self.dir
+            .join(format!("r.{}.{}.{}.vxr", region[0], region[1], region[2]))
+    }
+
+    /// Returns the saved chunk at `pos`, or `None` if it was never saved.
+    pub fn load_chunk(self: &Self, pos: [i32; 3]) -> io::Result<Option<Chunk>> {
+        let mut regions = self.regions.lock().unwrap();
+        let region = region_of(pos);
+        self.cache_region(&mut regions, region)?;
+        regions[&region].chunk(region_local(pos))
+    }
+
+    /// Writes the given chunks back, rewriting each region they fall in once.
+    pub fn save_chunks(self: &Self, chunks: &[([i32; 3], Chunk)]) -> io::Result<()> {
+        let mut regions = self.regions.lock().unwrap();
+        // materials first, so saved chunks never refer to a missing one
+        if self.persistent {
+            self.materials.save(&self.dir.join(MATERIAL_FILE))?;
+        }
+
+        let mut touched = HashSet::new();
+
+        for (pos, chunk) in chunks.iter() {
+            let region = region_of(*pos);
+            self.cache_region(&mut regions, region)?;
+
+            regions
+                .get_mut(&region)
+                .unwrap()
+                .set_chunk(region_local(*pos), chunk)?;
+            touched.insert(region);
+        }
+
+        if self.persistent {
+            for region in touched {
+                regions[&region].write(&self.region_path(region))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cache_region(
+        self: &Self,
+        regions: &mut HashMap<[i32; 3], Region>,
+        region: [i32; 3],
+    ) -> io::Result<()> {
+        if !regions.contains_key(&region) {
+            let path = self.region_path(region);
+            let loaded = if self.persistent && path.exists() {
+                Region::read(&path)?
+            } else {
+                Region::empty()
+            };
+            regions.insert(region, loaded);
+        }
+        Ok(())
+    }
+}
+
+pub fn region_of(chunk: [i32; 3]) -> [i32; 3] {
+    [
+        chunk[0].div_euclid(REGION_SIZE as i32),
+        chunk[1].div_euclid(REGION_SIZE as i32),
+        chunk[2].div_euclid(REGION_SIZE as i32),
+    ]
+}
+
+/// Position of a chunk inside its region.
+pub fn region_local(chunk: [i32; 3]) -> [u32; 3] {
+    [
+        chunk[0].rem_euclid(REGION_SIZE as i32) as u32,
+        chunk[1].rem_euclid(REGION_SIZE as i32) as u32,
+        chunk[2].rem_euclid(REGION_SIZE as i32) as u32,
+    ]
+}
+
+fn slot_index(local: [u32; 3]) -> usize {
+    ((local[0] * REGION_SIZE + local[1]) * REGION_SIZE + local[2]) as usize
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Work for the region IO thread.
+pub enum RegionRequest {
+    /// Replies with every chunk that could be read, `None` for chunks that
+    /// were never saved.
+    Load {
+        positions: Vec<[i32; 3]>,
+        reply: Sender<Vec<([i32; 3], Option<Chunk>)>>,
+    },
+    /// Replies with the positions once they are written, whether or not that
+    /// worked.
+    Save {
+        chunks: Vec<([i32; 3], Chunk)>,
+        reply: Option<Sender<Vec<[i32; 3]>>>,
+    },
+}
+
+/// Hands loads and saves to a single long-lived thread. Requests are handled
+/// one at a time in the order they were sent, so an older snapshot of a chunk
+/// can never be written over a newer one, and a load always sees the saves
+/// sent before it.
+#[derive(Resource, Clone)]
+pub struct RegionIo {
+    tx: Sender<RegionRequest>,
+}
+impl RegionIo {
+    pub fn start(store: RegionStore) -> Self {
+        let (tx, rx) = unbounded::<RegionRequest>();
+        thread::spawn(move || {
+            for request in rx {
+                match request {
+                    RegionRequest::Load { positions, reply } => {
+                        let mut chunks = Vec::with_capacity(positions.len());
+                        for pos in positions {
+                            match store.load_chunk(pos) {
+                                Ok(chunk) => chunks.push((pos, chunk)),
+                                Err(err) => info!("Error loading chunk {:?}: {}", pos, err),
+                            }
+                        }
+                        let _ = reply.send(chunks);
+                    }
+                    RegionRequest::Save { chunks, reply } => {
+                        let now = Instant::now();
+                        match store.save_chunks(&chunks) {
+                            Ok(_) => info!(
+                                "saving {} chunks took: {}",
+                                chunks.len(),
+                                now.elapsed().as_millis()
+                            ),
+                            Err(err) => info!("Error saving world: {}", err),
+                        }
+                        if let Some(reply) = reply {
+                            let _ = reply.send(chunks.into_iter().map(|(pos, _)| pos).collect());
+                        }
+                    }
+                }
+            }
+        });
+        RegionIo { tx }
+    }
+
+    pub fn load(
+        self: &Self,
+        positions: Vec<[i32; 3]>,
+        reply: Sender<Vec<([i32; 3], Option<Chunk>)>>,
+    ) {
+        if let Err(err) = self.tx.send(RegionRequest::Load { positions, reply }) {
+            info!("Error requesting chunks: {}", err);
+        }
+    }
+
+    pub fn save(self: &Self, chunks: Vec<([i32; 3], Chunk)>, reply: Option<Sender<Vec<[i32; 3]>>>) {
+        if let Err(err) = self.tx.send(RegionRequest::Save { chunks, reply }) {
+            info!("Error saving world: {}", err);
+        }
+    }
+}
+
+pub fn setup(mut commands: Commands) {
+    let store = match RegionStore::open(SAVE_DIR) {
+        Ok(store) => store,
+        Err(err) => {
+            info!(
+                "Error opening save directory {}: {}, the world will not be saved",
+                SAVE_DIR, err
+            );
+            RegionStore::in_memory()
+        }
+    };
+    commands.insert_resource(store.materials.clone());
+    commands.insert_resource(RegionIo::start(store.clone()));
+    commands.insert_resource(store);
+}
+
+pub fn save_dirty_chunks(
+    world: Res<VoxWorld>,
+    io: Res<RegionIo>,
+    time: Res<Time>,
+    mut timer: Local<f32>,
+) {
+    *timer += time.delta_seconds();
+    if *timer < SAVE_INTERVAL {
+        return;
+    }
+    *timer = 0.0;
+
+    // copy the chunks out right away, a chunk missing from the world now was
+    // emptied, while one unloaded later on is saved by the streamer itself
+    let chunks: Vec<([i32; 3], Chunk)> = {
+        let world_chunks = world.world.read().unwrap();
+        world
+            .dirty
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|pos| (pos, world_chunks.get(pos).cloned().unwrap_or_default()))
+            .collect()
+    };
+    if chunks.is_empty() {
+        return;
+    }
+    io.save(chunks, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world_generator::C_SIZE;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vxr_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn voxel(id: u8, color: [u8; 3], emission: f32, material: u16) -> StorageVoxel {
+        StorageVoxel {
+            id,
+            color,
+            emission,
+            material,
+        }
+    }
+
+    #[test]
+    fn saved_chunks_read_back_unchanged() {
+        let dir = temp_dir("round_trip");
+
+        let mut sparse = Chunk::default();
+        sparse.set([1, 2, 3], voxel(1, [10, 20, 30], 0.0, 0));
+        sparse.set([63, 0, 63], voxel(2, [255, 0, 7], 0.75, 3));
+        let mut dense = Chunk::default();
+        for x in 0..C_SIZE as u8 {
+            for y in 0..C_SIZE as u8 / 2 {
+                for z in 0..C_SIZE as u8 {
+                    dense.set([x, y, z], voxel(1 + (x + z) % 8, [x, y, z], 0.0, 0));
+                }
+            }
+        }
+        let chunks = vec![([0, 0, 0], sparse), ([1, -1, 9], dense)];
+
+        RegionStore::open(dir.clone())
+            .unwrap()
+            .save_chunks(&chunks)
+            .unwrap();
+        let reopened = RegionStore::open(dir.clone()).unwrap();
+        for (pos, chunk) in chunks.iter() {
+            let loaded = reopened.load_chunk(*pos).unwrap().unwrap();
+            assert!(loaded == *chunk);
+            assert_eq!(encode_chunk(&loaded).unwrap(), encode_chunk(chunk).unwrap());
+        }
+        assert!(reopened.load_chunk([2, 0, 0]).unwrap().is_none());
+
+        // saving what was read gives back the same file
+        let path = reopened.region_path([0, 0, 0]);
+        let before = fs::read(&path).unwrap();
+        reopened.save_chunks(&chunks[..1]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let dir = temp_dir("versions");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.0.0.0.vxr");
+        Region::empty().write(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert!(Region::read(&path).is_ok());
+        for version in [0, REGION_VERSION + 1] {
+            let mut bytes = bytes.clone();
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+            fs::write(&path, bytes).unwrap();
+            assert!(Region::read(&path).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,650 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    fluid::fluid_of,
+    generate_octree::GenerateOctreeEvent,
+    player_controller::PCamera,
+    streaming::{ChunkStreamer, FloatingOrigin},
+    world_generator::{
+        chunk_of, ChunkMap, EditSource, StorageVoxel, VoxWorld, VoxelEntity, VoxelsChanged,
+    },
+};
+
+/// Mass of a single voxel.
+pub const VOXEL_MASS: f32 = 1.0;
+/// Steps per second, frames are split into steps of at most this length.
+const STEP_RATE: f32 = 120.0;
+/// Bodies slower than this for `SLEEP_TIME` seconds stop being simulated.
+const SLEEP_SPEED: f32 = 0.1;
+const SLEEP_TIME: f32 = 1.0;
+
+#[derive(Resource, Clone)]
+pub struct RigidBodySettings {
+    pub enabled: bool,
+    /// Voxels per second squared.
+    pub gravity: Vec3,
+    pub restitution: f32,
+    pub friction: f32,
+    /// Speed of props thrown with `T`.
+    pub throw_speed: f32,
+}
+impl Default for RigidBodySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gravity: Vec3::new(0.0, -30.0, 0.0),
+            restitution: 0.2,
+            friction: 0.6,
+            throw_speed: 60.0,
+        }
+    }
+}
+
+/// Motion of a `VoxelEntity`. Mass and inertia follow from its voxels, the
+/// entity's transform is its pose.
+#[derive(Component, Clone)]
+pub struct RigidBody {
+    pub velocity: Vec3,
+    pub angular_velocity: Vec3,
+    pub mass: f32,
+    /// Inertia about the centre of mass, the same around every axis.
+    pub inertia: f32,
+    /// Centre of mass relative to the entity's transform, unrotated.
+    pub center_of_mass: Vec3,
+    /// Distance of the furthest voxel from the centre of mass.
+    pub radius: f32,
+    pub sleeping: bool,
+    /// Put the voxels back into the world once the body falls asleep, see
+    /// `merge_sleeping_bodies`. Bodies that remove themselves, like fading
+    /// debris, turn this off.
+    pub merge_on_sleep: bool,
+    still_time: f32,
+}
+impl RigidBody {
+    pub fn from_entity(entity: &VoxelEntity) -> Self {
+        let count = entity.voxels.len().max(1) as f32;
+        let centers: Vec<Vec3> = entity
+            .voxels
+            .iter()
+            .map(|(offset, _)| voxel_center(*offset))
+            .collect();
+        let center_of_mass = centers.iter().copied().sum::<Vec3>() / count;
+        let spread: f32 = centers
+            .iter()
+            .map(|c| c.distance_squared(center_of_mass))
+            .sum();
+        let radius = centers
+            .iter()
+            .map(|c| c.distance(center_of_mass))
+            .fold(0.0, f32::max)
+            + 0.87;
+
+        RigidBody {
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            mass: count * VOXEL_MASS,
+            // a solid averaged over all axes, plus each voxel's own cube
+            inertia: VOXEL_MASS * (spread * 2.0 / 3.0 + count / 6.0),
+            center_of_mass,
+            radius,
+            sleeping: false,
+            merge_on_sleep: true,
+            still_time: 0.0,
+        }
+    }
+
+    pub fn wake(self: &mut Self) {
+        self.sleeping = false;
+        self.still_time = 0.0;
+    }
+
+    pub fn world_center(self: &Self, transform: &Transform) -> Vec3 {
+        transform.transform_point(self.center_of_mass)
+    }
+
+    fn point_velocity(self: &Self, transform: &Transform, point: Vec3) -> Vec3 {
+        self.velocity
+            + self
+                .angular_velocity
+                .cross(point - self.world_center(transform))
+    }
+
+    /// Inverse of the effective mass for an impulse along `n` at offset `r`
+    /// from the centre of mass.
+    fn inverse_mass(self: &Self, r: Vec3, n: Vec3) -> f32 {
+        1.0 / self.mass + r.cross(n).length_squared() / self.inertia
+    }
+}
+
+/// Centre of the voxel at `offset` relative to its entity.
+fn voxel_center(offset: [i32; 3]) -> Vec3 {
+    Vec3::new(offset[0] as f32, offset[1] as f32, offset[2] as f32) + Vec3::splat(0.5)
+}
+
+/// A contact with the world or another body, in local coordinates. `normal`
+/// points away from what was hit.
+struct Contact {
+    point: Vec3,
+    normal: Vec3,
+    depth: f32,
+}
+
+/// Integrates gravity and velocity, then resolves voxel contacts against the
+/// world and between bodies with impulses. Bodies that come to rest sleep
+/// until an edit near them or a hit from another body wakes them. Bodies
+/// reaching into chunks that have not arrived are frozen, so they don't fall
+/// through terrain that is still on its way.
+pub fn step_rigid_bodies(
+    time: Res<Time>,
+    settings: Res<RigidBodySettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    streamer: Res<ChunkStreamer>,
+    mut changes: EventReader<VoxelsChanged>,
+    mut bodies: Query<(&mut VoxelEntity, &mut RigidBody)>,
+    mut event_writer: EventWriter<GenerateOctreeEvent>,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    // edits near a body may have taken away what it rests on
+    for change in changes.read() {
+        let min = origin.to_local(change.min);
+        let max = origin.to_local(change.max) + Vec3::ONE;
+        for (entity, mut body) in bodies.iter_mut() {
+            let center = body.world_center(&entity.transform);
+            if center.clamp(min, max).distance(center) <= body.radius + 1.0 {
+                body.wake();
+            }
+        }
+    }
+
+    let dt = time.delta_seconds().min(0.1);
+    let steps = (dt * STEP_RATE).ceil().max(1.0) as u32;
+    let h = dt / steps as f32;
+    let mut moved = false;
+
+    let chunks = world.world.read().unwrap();
+    for _ in 0..steps {
+        for (mut entity, mut body) in bodies.iter_mut() {
+            if body.sleeping {
+                continue;
+            }
+            let center = origin.to_world(body.world_center(&entity.transform));
+            if !chunks_arrived(&streamer, center, body.radius + 1.0) {
+                continue;
+            }
+            moved = true;
+            step_body(&settings, &chunks, &origin, &mut entity, &mut body, h);
+        }
+        collide_bodies(&settings, &mut bodies);
+    }
+
+    if moved {
+        event_writer.send(GenerateOctreeEvent);
+    }
+}
+
+/// Whether every chunk within `radius` of a world position has arrived.
+fn chunks_arrived(streamer: &ChunkStreamer, center: Vec3, radius: f32) -> bool {
+    let min = chunk_of((center - radius).floor().as_ivec3().to_array()).0;
+    let max = chunk_of((center + radius).floor().as_ivec3().to_array()).0;
+    for x in min[0]..=max[0] {
+        for y in min[1]..=max[1] {
+            for z in min[2]..=max[2] {
+                if !streamer.has_arrived([x, y, z]) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Advances one awake body by `h` seconds against the world, and puts it to
+/// sleep once it has been still for long enough.
+fn step_body(
+    settings: &RigidBodySettings,
+    chunks: &ChunkMap,
+    origin: &FloatingOrigin,
+    entity: &mut VoxelEntity,
+    body: &mut RigidBody,
+    h: f32,
+) {
+    body.velocity += settings.gravity * h;
+    let center = body.world_center(&entity.transform);
+    entity.transform.translation += body.velocity * h;
+    let spin = Quat::from_scaled_axis(body.angular_velocity * h);
+    entity.transform.rotation = (spin * entity.transform.rotation).normalize();
+    // rotate about the centre of mass, not the transform's origin
+    let new_center = body.world_center(&entity.transform);
+    entity.transform.translation += center + body.velocity * h - new_center;
+
+    let contacts = world_contacts(chunks, origin, entity);
+    resolve_contacts(settings, &contacts, entity, body);
+
+    let still =
+        body.velocity.length() < SLEEP_SPEED && body.angular_velocity.length() < SLEEP_SPEED;
+    body.still_time = if still { body.still_time + h } else { 0.0 };
+    if body.still_time > SLEEP_TIME {
+        body.sleeping = true;
+        body.velocity = Vec3::ZERO;
+        body.angular_velocity = Vec3::ZERO;
+    }
+}
+
+/// Bakes bodies that fell asleep back into the world and despawns them, see
+/// `merged_voxels`.
+pub fn merge_sleeping_bodies(
+    mut commands: Commands,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    bodies: Query<(Entity, &VoxelEntity, &RigidBody)>,
+) {
+    let mut edits = Vec::new();
+    {
+        let chunks = world.world.read().unwrap();
+        for (entity, vox_entity, body) in bodies.iter() {
+            if !body.sleeping || !body.merge_on_sleep {
+                continue;
+            }
+            edits.extend(
+                merged_voxels(&chunks, &origin, vox_entity)
+                    .into_iter()
+                    .map(|(cell, vox)| (cell, Some(vox))),
+            );
+            commands.entity(entity).despawn();
+        }
+    }
+    if !edits.is_empty() {
+        world.apply_from(edits, EditSource::Simulation);
+    }
+}
+
+/// World cells the voxels of a body go to when it is baked into the world.
+/// Each voxel is snapped to the cell its centre is in. A resting body sinks
+/// a little into what it rests on, so the whole body is moved by the nearest
+/// whole-voxel offset, preferring up, that leaves every cell free. Cells
+/// that are still taken after that keep what is there.
+fn merged_voxels(
+    chunks: &ChunkMap,
+    origin: &FloatingOrigin,
+    entity: &VoxelEntity,
+) -> Vec<([i32; 3], StorageVoxel)> {
+    let cells: Vec<IVec3> = entity
+        .voxels
+        .iter()
+        .map(|(offset, _)| {
+            entity
+                .transform
+                .transform_point(voxel_center(*offset))
+                .floor()
+                .as_ivec3()
+        })
+        .collect();
+    let taken = |cell: IVec3| chunks.get_voxel(origin_cell(origin, cell)).is_some();
+
+    let mut shifts = Vec::new();
+    for x in -2..=2 {
+        for y in -2..=2 {
+            for z in -2..=2 {
+                shifts.push(IVec3::new(x, y, z));
+            }
+        }
+    }
+    shifts.sort_by_key(|shift| (shift.length_squared(), -shift.y));
+    let shift = shifts
+        .into_iter()
+        .find(|shift| cells.iter().all(|cell| !taken(*cell + *shift)))
+        .unwrap_or(IVec3::ZERO);
+
+    let mut merged: HashMap<[i32; 3], StorageVoxel> = HashMap::new();
+    for (cell, (_, vox)) in cells.into_iter().zip(entity.voxels.iter()) {
+        let cell = cell + shift;
+        if !taken(cell) {
+            merged.insert(origin_cell(origin, cell), vox.clone());
+        }
+    }
+    merged.into_iter().collect()
+}
+
+/// Voxels of the entity that ended up inside a solid world voxel. Fluids do
+/// not stop bodies.
+fn world_contacts(
+    chunks: &ChunkMap,
+    origin: &FloatingOrigin,
+    entity: &VoxelEntity,
+) -> Vec<Contact> {
+    let solid = |cell: IVec3| {
+        chunks
+            .get_voxel(origin_cell(origin, cell))
+            .map_or(false, |vox| fluid_of(vox).is_none())
+    };
+
+    let mut contacts = Vec::new();
+    for (offset, _) in entity.voxels.iter() {
+        let point = entity.transform.transform_point(voxel_center(*offset));
+        let cell = point.floor().as_ivec3();
+        if !solid(cell) {
+            continue;
+        }
+
+        // out through the open faces of the cell that was hit
+        let mut normal = Vec3::ZERO;
+        for dir in [
+            IVec3::X,
+            IVec3::NEG_X,
+            IVec3::Y,
+            IVec3::NEG_Y,
+            IVec3::Z,
+            IVec3::NEG_Z,
+        ] {
+            if !solid(cell + dir) {
+                normal += dir.as_vec3();
+            }
+        }
+        let normal = normal.try_normalize().unwrap_or(Vec3::Y);
+        let cell_center = cell.as_vec3() + Vec3::splat(0.5);
+        let depth = ((cell_center - point).dot(normal) + 0.5).max(0.0);
+        contacts.push(Contact {
+            point,
+            normal,
+            depth,
+        });
+    }
+    contacts
+}
+
+fn origin_cell(origin: &FloatingOrigin, local: IVec3) -> [i32; 3] {
+    [
+        local.x + origin.origin[0],
+        local.y + origin.origin[1],
+        local.z + origin.origin[2],
+    ]
+}
+
+/// One pass of impulses against static geometry, then pushes the body out by
+/// the average depth.
+fn resolve_contacts(
+    settings: &RigidBodySettings,
+    contacts: &[Contact],
+    entity: &mut VoxelEntity,
+    body: &mut RigidBody,
+) {
+    if contacts.is_empty() {
+        return;
+    }
+    let share = 1.0 / contacts.len() as f32;
+    let mut correction = Vec3::ZERO;
+    for contact in contacts.iter() {
+        let r = contact.point - body.world_center(&entity.transform);
+        let v = body.point_velocity(&entity.transform, contact.point);
+        let vn = v.dot(contact.normal);
+        correction += contact.normal * contact.depth * share;
+        if vn >= 0.0 {
+            continue;
+        }
+
+        let j = -(1.0 + settings.restitution) * vn / body.inverse_mass(r, contact.normal) * share;
+        let mut impulse = contact.normal * j;
+
+        let tangent_v = v - contact.normal * vn;
+        if let Some(t) = tangent_v.try_normalize() {
+            let jt =
+                (tangent_v.length() / body.inverse_mass(r, t) * share).min(settings.friction * j);
+            impulse -= t * jt;
+        }
+        body.velocity += impulse / body.mass;
+        body.angular_velocity += r.cross(impulse) / body.inertia;
+    }
+    entity.transform.translation += correction * 0.8;
+}
+
+/// Voxel contacts between bodies: any two voxels of different bodies in the
+/// same cell push the bodies apart along the line between their centres.
+fn collide_bodies(
+    settings: &RigidBodySettings,
+    bodies: &mut Query<(&mut VoxelEntity, &mut RigidBody)>,
+) {
+    let mut all: Vec<_> = bodies.iter_mut().collect();
+
+    // sleeping bodies only need their cells when an awake body is close
+    let awake: Vec<(Vec3, f32)> = all
+        .iter()
+        .filter(|(_, body)| !body.sleeping)
+        .map(|(entity, body)| (body.world_center(&entity.transform), body.radius))
+        .collect();
+    if awake.is_empty() {
+        return;
+    }
+
+    let mut cells: HashMap<IVec3, usize> = HashMap::new();
+    let mut hits: HashMap<(usize, usize), Vec<Vec3>> = HashMap::new();
+    for (i, (entity, body)) in all.iter().enumerate() {
+        if body.sleeping {
+            let center = body.world_center(&entity.transform);
+            let near = awake
+                .iter()
+                .any(|(c, r)| c.distance(center) <= r + body.radius);
+            if !near {
+                continue;
+            }
+        }
+        for (offset, _) in entity.voxels.iter() {
+            let point = entity.transform.transform_point(voxel_center(*offset));
+            let cell = point.floor().as_ivec3();
+            match cells.get(&cell) {
+                Some(other) if *other != i => {
+                    hits.entry((*other, i)).or_default().push(point);
+                }
+                _ => {
+                    cells.insert(cell, i);
+                }
+            }
+        }
+    }
+
+    for ((a, b), points) in hits {
+        // cells are claimed in order, so a < b
+        let (left, right) = all.split_at_mut(b);
+        let (entity_a, body_a) = &mut left[a];
+        let (entity_b, body_b) = &mut right[0];
+        if body_a.sleeping && body_b.sleeping {
+            continue;
+        }
+
+        let center_a = body_a.world_center(&entity_a.transform);
+        let center_b = body_b.world_center(&entity_b.transform);
+        let normal = (center_b - center_a).try_normalize().unwrap_or(Vec3::Y);
+        let share = 1.0 / points.len() as f32;
+        for point in points.iter() {
+            let ra = *point - center_a;
+            let rb = *point - center_b;
+            let v = body_b.point_velocity(&entity_b.transform, *point)
+                - body_a.point_velocity(&entity_a.transform, *point);
+            let vn = v.dot(normal);
+            if vn >= 0.0 {
+                continue;
+            }
+            let j = -(1.0 + settings.restitution) * vn
+                / (body_a.inverse_mass(ra, normal) + body_b.inverse_mass(rb, normal))
+                * share;
+            let impulse = normal * j;
+            body_a.velocity -= impulse / body_a.mass;
+            body_a.angular_velocity -= ra.cross(impulse) / body_a.inertia;
+            body_b.velocity += impulse / body_b.mass;
+            body_b.angular_velocity += rb.cross(impulse) / body_b.inertia;
+        }
+        // push apart by a fraction of a voxel, the lighter body moves more
+        let total = body_a.mass + body_b.mass;
+        entity_a.transform.translation -= normal * 0.25 * body_b.mass / total;
+        entity_b.transform.translation += normal * 0.25 * body_a.mass / total;
+        body_a.wake();
+        body_b.wake();
+    }
+}
+
+/// `T` throws a small crate from the camera.
+pub fn throw_props(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<RigidBodySettings>,
+    camera: Query<&GlobalTransform, With<PCamera>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyT) {
+        return;
+    }
+    let camera = match camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    let crate_voxel = StorageVoxel {
+        id: 2,
+        color: [111, 67, 16],
+        emission: 0.0,
+        material: 0,
+    };
+    let mut voxels = Vec::new();
+    for x in 0..4 {
+        for y in 0..4 {
+            for z in 0..4 {
+                voxels.push(([x, y, z], crate_voxel.clone()));
+            }
+        }
+    }
+    let forward = camera.forward().as_vec3();
+    let entity = VoxelEntity {
+        name: "crate".to_string(),
+        transform: Transform::from_translation(
+            camera.translation() + forward * 4.0 - Vec3::splat(2.0),
+        ),
+        voxels,
+    };
+    let mut body = RigidBody::from_entity(&entity);
+    body.velocity = forward * settings.throw_speed;
+    body.angular_velocity = camera.right().as_vec3() * 2.0;
+    commands.spawn((entity, body));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crate_entity(position: Vec3) -> VoxelEntity {
+        let vox = StorageVoxel {
+            id: 2,
+            color: [111, 67, 16],
+            emission: 0.0,
+            material: 0,
+        };
+        let mut voxels = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    voxels.push(([x, y, z], vox.clone()));
+                }
+            }
+        }
+        VoxelEntity {
+            name: "crate".to_string(),
+            transform: Transform::from_translation(position),
+            voxels,
+        }
+    }
+
+    /// A floor whose top is at y = 0.
+    fn floor() -> ChunkMap {
+        let mut chunks = ChunkMap::default();
+        let stone = StorageVoxel {
+            id: 1,
+            color: [121, 121, 121],
+            emission: 0.0,
+            material: 0,
+        };
+        for x in -16..16 {
+            for z in -16..16 {
+                chunks.set_voxel([x, -1, z], Some(stone.clone()));
+            }
+        }
+        chunks
+    }
+
+    #[test]
+    fn crate_dropped_on_a_floor_comes_to_rest() {
+        let settings = RigidBodySettings::default();
+        let chunks = floor();
+        let origin = FloatingOrigin::default();
+        let mut entity = crate_entity(Vec3::new(-2.0, 6.0, -2.0));
+        let mut body = RigidBody::from_entity(&entity);
+
+        let h = 1.0 / STEP_RATE;
+        for _ in 0..(STEP_RATE * 5.0) as u32 {
+            if body.sleeping {
+                break;
+            }
+            step_body(&settings, &chunks, &origin, &mut entity, &mut body, h);
+        }
+
+        assert!(body.sleeping);
+        // contacts are found at voxel centres, so the crate settles with its
+        // bottom about half a voxel into the floor
+        let bottom = entity.transform.translation.y;
+        assert!(bottom > -1.0 && bottom < 0.0, "bottom at {}", bottom);
+        assert!(entity.transform.rotation.angle_between(Quat::IDENTITY) < 0.1);
+    }
+
+    #[test]
+    fn merged_crate_keeps_every_voxel() {
+        let settings = RigidBodySettings::default();
+        let chunks = floor();
+        let origin = FloatingOrigin::default();
+        let mut entity = crate_entity(Vec3::new(-2.0, 6.0, -2.0));
+        let mut body = RigidBody::from_entity(&entity);
+        for _ in 0..(STEP_RATE * 5.0) as u32 {
+            if body.sleeping {
+                break;
+            }
+            step_body(
+                &settings,
+                &chunks,
+                &origin,
+                &mut entity,
+                &mut body,
+                1.0 / STEP_RATE,
+            );
+        }
+        assert!(body.sleeping);
+
+        let merged = merged_voxels(&chunks, &origin, &entity);
+        assert_eq!(merged.len(), entity.voxels.len());
+        assert!(merged
+            .iter()
+            .all(|(cell, _)| chunks.get_voxel(*cell).is_none()));
+        // sitting on the floor, not above it
+        assert_eq!(merged.iter().map(|(cell, _)| cell[1]).min(), Some(0));
+    }
+
+    #[test]
+    fn crate_in_empty_space_keeps_falling() {
+        let settings = RigidBodySettings::default();
+        let chunks = ChunkMap::default();
+        let origin = FloatingOrigin::default();
+        let mut entity = crate_entity(Vec3::ZERO);
+        let mut body = RigidBody::from_entity(&entity);
+
+        for _ in 0..STEP_RATE as u32 {
+            step_body(
+                &settings,
+                &chunks,
+                &origin,
+                &mut entity,
+                &mut body,
+                1.0 / STEP_RATE,
+            );
+        }
+        assert!(!body.sleeping);
+        assert!(entity.transform.translation.y < -10.0);
+    }
+}
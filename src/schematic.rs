@@ -0,0 +1,411 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use crate::{
+    material::{MaterialTable, VoxMaterial},
+    region::invalid_data,
+    world_generator::{color_voxel, StorageVoxel, WorldData},
+};
+
+pub const BLOCK_TABLE: &str = "Assets/blocks.ron";
+
+/// How one block looks in the world. Colours are 0-255 RGB and go through the
+/// same conversion as `.vox` colours, `id` overrides the id that would be
+/// picked from the colour. Granular blocks fall when unsupported.
+#[derive(Clone, Deserialize)]
+pub struct BlockMaterial {
+    pub color: [u8; 3],
+    #[serde(default)]
+    pub emission: f32,
+    #[serde(default)]
+    pub id: Option<u8>,
+    #[serde(default)]
+    pub granular: bool,
+}
+impl BlockMaterial {
+    pub fn voxel(self: &Self, materials: &MaterialTable) -> StorageVoxel {
+        let [r, g, b] = self.color;
+        let mut vox = color_voxel(dot_vox::Color { r, g, b, a: 255 }, self.emission);
+        if let Some(id) = self.id {
+            vox.id = id;
+        }
+        if self.granular {
+            vox.material = materials.index_of(VoxMaterial {
+                granular: true,
+                ..VoxMaterial::default()
+            });
+        }
+        vox
+    }
+}
+
+/// Block to material mapping, read from `BLOCK_TABLE`. Keys are either a full
+/// block state like `minecraft:lava[level=0]` or just the block name, the
+/// full state wins. Blocks that are in neither use `default`, or are left
+/// out when there is no default.
+#[derive(Clone, Deserialize)]
+pub struct BlockTable {
+    #[serde(default)]
+    pub default: Option<BlockMaterial>,
+    #[serde(default)]
+    pub blocks: HashMap<String, BlockMaterial>,
+    /// Blocks that are never placed, like air.
+    #[serde(default)]
+    pub skip: Vec<String>,
+}
+impl Default for BlockTable {
+    fn default() -> Self {
+        BlockTable {
+            default: Some(BlockMaterial {
+                color: [121, 121, 121],
+                emission: 0.0,
+                id: None,
+                granular: false,
+            }),
+            blocks: HashMap::new(),
+            skip: vec![
+                "minecraft:air".to_string(),
+                "minecraft:cave_air".to_string(),
+                "minecraft:void_air".to_string(),
+            ],
+        }
+    }
+}
+impl BlockTable {
+    pub fn load(path: &str) -> Self {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                info!("Error reading block table {}: {}", path, err);
+                return BlockTable::default();
+            }
+        };
+        match ron::from_str(&text) {
+            Ok(table) => table,
+            Err(err) => {
+                info!("Error parsing block table {}: {}", path, err);
+                BlockTable::default()
+            }
+        }
+    }
+
+    pub fn voxel(self: &Self, state: &str, materials: &MaterialTable) -> Option<StorageVoxel> {
+        let name = state.split('[').next().unwrap_or(state);
+        if self.skip.iter().any(|s| s == name || s == state) {
+            return None;
+        }
+        self.blocks
+            .get(state)
+            .or_else(|| self.blocks.get(name))
+            .or(self.default.as_ref())
+            .map(|material| material.voxel(materials))
+    }
+}
+
+/// Reads a Sponge `.schem` file (version 2 or 3) and places its blocks into
+/// the world with their minimum corner at `offset`. Returns how many voxels
+/// were placed.
+pub fn load_schematic(
+    path: &Path,
+    table: &BlockTable,
+    materials: &MaterialTable,
+    offset: [i32; 3],
+    world: &mut WorldData,
+) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    GzDecoder::new(fs::File::open(path)?).read_to_end(&mut bytes)?;
+    let root = Nbt::read_root(&bytes)?;
+    // version 3 wraps everything in a "Schematic" compound
+    let schematic = root.get("Schematic").unwrap_or(&root);
+
+    let width = schematic.int("Width")? as usize;
+    let height = schematic.int("Height")? as usize;
+    let length = schematic.int("Length")? as usize;
+    let blocks = schematic.get("Blocks").unwrap_or(schematic);
+    let palette = blocks
+        .get("Palette")
+        .ok_or_else(|| invalid_data("schematic has no block palette"))?;
+    let data = match blocks.get("Data").or_else(|| blocks.get("BlockData")) {
+        Some(Nbt::ByteArray(data)) => data,
+        _ => return Err(invalid_data("schematic has no block data")),
+    };
+
+    // palette ids to voxels, looked up once per block state
+    let mut voxels: HashMap<u32, Option<StorageVoxel>> = HashMap::new();
+    if let Nbt::Compound(entries) = palette {
+        for (state, id) in entries.iter() {
+            if let Nbt::Int(id) = id {
+                voxels.insert(*id as u32, table.voxel(state, materials));
+            }
+        }
+    }
+
+    let mut placed = 0;
+    let mut at = 0;
+    for index in 0..width * height * length {
+        let id = read_varint(data, &mut at)?;
+        let vox = match voxels.get(&id) {
+            Some(Some(vox)) => vox,
+            Some(None) => continue,
+            None => return Err(invalid_data(&format!("block id {} not in palette", id))),
+        };
+        let x = index % width;
+        let z = (index / width) % length;
+        let y = index / (width * length);
+        world.data.set_voxel(
+            [
+                offset[0] + x as i32,
+                offset[1] + y as i32,
+                offset[2] + z as i32,
+            ],
+            Some(vox.clone()),
+        );
+        placed += 1;
+    }
+
+    Ok(placed)
+}
+
+fn read_varint(data: &[i8], at: &mut usize) -> io::Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *data
+            .get(*at)
+            .ok_or_else(|| invalid_data("block data ends early"))? as u8;
+        *at += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+/// The subset of NBT needed to read schematics. Numbers are big endian.
+/// Every tag is parsed so the reader stays in step, the kinds that are never
+/// looked at are kept as `Skipped`.
+enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    Compound(HashMap<String, Nbt>),
+    /// Strings, lists, int and long arrays.
+    Skipped,
+}
+impl Nbt {
+    fn read_root(bytes: &[u8]) -> io::Result<Nbt> {
+        let mut reader = NbtReader { bytes, at: 0 };
+        if reader.u8()? != 10 {
+            return Err(invalid_data("NBT root is not a compound"));
+        }
+        reader.string()?;
+        reader.payload(10, 0)
+    }
+
+    fn get(self: &Self, key: &str) -> Option<&Nbt> {
+        match self {
+            Nbt::Compound(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    fn int(self: &Self, key: &str) -> io::Result<i32> {
+        match self.get(key) {
+            Some(Nbt::Byte(v)) => Ok(*v as i32),
+            // sizes are unsigned shorts
+            Some(Nbt::Short(v)) => Ok(*v as u16 as i32),
+            Some(Nbt::Int(v)) => Ok(*v),
+            Some(Nbt::Long(v)) => Ok(*v as i32),
+            Some(Nbt::Float(v)) => Ok(*v as i32),
+            Some(Nbt::Double(v)) => Ok(*v as i32),
+            _ => Err(invalid_data(&format!("missing number {}", key))),
+        }
+    }
+}
+
+struct NbtReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+impl<'a> NbtReader<'a> {
+    fn take(self: &mut Self, len: usize) -> io::Result<&'a [u8]> {
+        if self.at + len > self.bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "NBT data ends early",
+            ));
+        }
+        let bytes = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(self: &mut Self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(self: &mut Self) -> io::Result<i16> {
+        let b = self.take(2)?;
+        Ok(i16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i32(self: &mut Self) -> io::Result<i32> {
+        let b = self.take(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i64(self: &mut Self) -> io::Result<i64> {
+        let b = self.take(8)?;
+        Ok(i64::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn array_len(self: &mut Self) -> io::Result<usize> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Err(invalid_data("negative NBT length"));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(self: &mut Self) -> io::Result<String> {
+        let len = self.i16()? as u16 as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn payload(self: &mut Self, tag: u8, depth: u32) -> io::Result<Nbt> {
+        if depth > 512 {
+            return Err(invalid_data("NBT nested too deep"));
+        }
+        Ok(match tag {
+            1 => Nbt::Byte(self.u8()? as i8),
+            2 => Nbt::Short(self.i16()?),
+            3 => Nbt::Int(self.i32()?),
+            4 => Nbt::Long(self.i64()?),
+            5 => Nbt::Float(f32::from_bits(self.i32()? as u32)),
+            6 => Nbt::Double(f64::from_bits(self.i64()? as u64)),
+            7 => {
+                let len = self.array_len()?;
+                Nbt::ByteArray(self.take(len)?.iter().map(|b| *b as i8).collect())
+            }
+            8 => {
+                self.string()?;
+                Nbt::Skipped
+            }
+            9 => {
+                let tag = self.u8()?;
+                let len = self.array_len()?;
+                for _ in 0..len {
+                    self.payload(tag, depth + 1)?;
+                }
+                Nbt::Skipped
+            }
+            10 => {
+                let mut entries = HashMap::new();
+                loop {
+                    let tag = self.u8()?;
+                    if tag == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    entries.insert(name, self.payload(tag, depth + 1)?);
+                }
+                Nbt::Compound(entries)
+            }
+            11 => {
+                let len = self.array_len()?;
+                self.take(len.saturating_mul(4))?;
+                Nbt::Skipped
+            }
+            12 => {
+                let len = self.array_len()?;
+                self.take(len.saturating_mul(8))?;
+                Nbt::Skipped
+            }
+            tag => return Err(invalid_data(&format!("unknown NBT tag {}", tag))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stone and glowstone, with glowstone at palette id 300 so its block
+    /// data takes a two byte varint.
+    fn table() -> BlockTable {
+        let mut table = BlockTable::default();
+        table.blocks.insert(
+            "minecraft:glowstone".to_string(),
+            BlockMaterial {
+                color: [255, 200, 100],
+                emission: 3.0,
+                id: None,
+                granular: false,
+            },
+        );
+        table
+    }
+
+    fn load(name: &str) -> WorldData {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let mut world = WorldData::default();
+        let placed = load_schematic(
+            &path,
+            &table(),
+            &MaterialTable::default(),
+            [10, 20, 30],
+            &mut world,
+        )
+        .unwrap();
+        assert_eq!(placed, 3);
+        world
+    }
+
+    fn assert_blocks(world: &WorldData) {
+        let stone = world.data.get_voxel([10, 20, 30]).unwrap();
+        assert_eq!(stone.color, [121, 121, 121]);
+        assert_eq!(stone.emission, 0.0);
+        let glowstone = world.data.get_voxel([11, 20, 30]).unwrap();
+        assert_eq!(glowstone.color, [255, 200, 100]);
+        assert_eq!(glowstone.emission, 3.0);
+        // air is skipped
+        assert!(world.data.get_voxel([10, 21, 30]).is_none());
+        assert!(world.data.get_voxel([11, 21, 30]).is_some());
+    }
+
+    #[test]
+    fn reads_version_2_schematics() {
+        assert_blocks(&load("schematic_v2.schem"));
+    }
+
+    #[test]
+    fn reads_version_3_schematics() {
+        assert_blocks(&load("schematic_v3.schem"));
+    }
+
+    #[test]
+    fn reads_multi_byte_varints() {
+        let data: Vec<i8> = [0xac, 0x02, 0x01, 0xff, 0xff, 0xff, 0xff, 0x0f]
+            .iter()
+            .map(|b| *b as i8)
+            .collect();
+        let mut at = 0;
+        assert_eq!(read_varint(&data, &mut at).unwrap(), 300);
+        assert_eq!(read_varint(&data, &mut at).unwrap(), 1);
+        assert_eq!(read_varint(&data, &mut at).unwrap(), u32::MAX);
+        assert!(read_varint(&data, &mut at).is_err());
+    }
+}
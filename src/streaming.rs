@@ -0,0 +1,319 @@
+use std::thread;
+
+use bevy::{prelude::*, utils::HashSet};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::{
+    chunk::Chunk,
+    generate_octree::GenerateOctreeEvent,
+    particles::Particles,
+    player_controller::{PCamera, Player},
+    region::{RegionIo, RegionStore},
+    world_generator::{generate_chunk, TerrainSettings, VoxWorld, VoxelEntity, C_SIZE, RENDERDIST},
+};
+
+#[derive(Resource, Clone)]
+pub struct StreamingSettings {
+    /// Chunks within this many chunks of the camera are loaded or generated.
+    pub load_radius: i32,
+    /// Chunks further away than this are saved if needed and dropped. Keep it
+    /// larger than `load_radius` so chunks on the edge do not churn.
+    pub unload_radius: i32,
+    /// How far the player may get from the floating origin before everything
+    /// is shifted back towards it.
+    pub recenter_distance: f32,
+}
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            load_radius: (RENDERDIST / C_SIZE) as i32 + 1,
+            unload_radius: (RENDERDIST / C_SIZE) as i32 + 3,
+            recenter_distance: 1024.0,
+        }
+    }
+}
+
+/// World voxel position that local coordinates (transforms, the octree and
+/// the shader) are relative to. Moving it keeps f32 positions small no matter
+/// how far from the world origin the player goes.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct FloatingOrigin {
+    pub origin: [i32; 3],
+}
+impl FloatingOrigin {
+    pub fn to_world(self: &Self, local: Vec3) -> Vec3 {
+        local
+            + Vec3::new(
+                self.origin[0] as f32,
+                self.origin[1] as f32,
+                self.origin[2] as f32,
+            )
+    }
+
+    pub fn to_local(self: &Self, world: [i32; 3]) -> Vec3 {
+        Vec3::new(
+            (world[0] - self.origin[0]) as f32,
+            (world[1] - self.origin[1]) as f32,
+            (world[2] - self.origin[2]) as f32,
+        )
+    }
+}
+
+#[derive(Resource)]
+pub struct ChunkStreamer {
+    /// The world was loaded from a save, so the scene is not generated again.
+    pub from_save: bool,
+    /// Chunks that are in memory or on their way there.
+    loaded: HashSet<[i32; 3]>,
+    /// The part of `loaded` that is in `VoxWorld`, or turned out to be empty.
+    arrived: HashSet<[i32; 3]>,
+    /// Chunks being written to disk after unloading, they are not loaded
+    /// again until the write is done.
+    unloading: HashSet<[i32; 3]>,
+    load_tx: Sender<Vec<([i32; 3], Chunk)>>,
+    load_rx: Receiver<Vec<([i32; 3], Chunk)>>,
+    /// Replies from the region IO thread, chunks that were never saved come
+    /// back as `None` and are handed to the generators.
+    found_tx: Sender<Vec<([i32; 3], Option<Chunk>)>>,
+    found_rx: Receiver<Vec<([i32; 3], Option<Chunk>)>>,
+    generate_tx: Sender<([i32; 3], TerrainSettings)>,
+    saved_tx: Sender<Vec<[i32; 3]>>,
+    saved_rx: Receiver<Vec<[i32; 3]>>,
+}
+impl ChunkStreamer {
+    pub fn mark_loaded(self: &mut Self, pos: [i32; 3]) {
+        self.loaded.insert(pos);
+        self.arrived.insert(pos);
+    }
+
+    /// Whether a chunk is in memory, so `VoxWorld` holds all of its voxels.
+    /// Chunks that were only requested don't count, anything simulated
+    /// against them would fall or flow into empty space.
+    pub fn has_arrived(self: &Self, pos: [i32; 3]) -> bool {
+        self.arrived.contains(&pos)
+    }
+}
+
+/// Starts a fixed set of terrain generator threads, one per core left over
+/// after the main and IO threads.
+pub fn setup(mut commands: Commands, store: Res<RegionStore>) {
+    let (load_tx, load_rx) = unbounded();
+    let (found_tx, found_rx) = unbounded();
+    let (generate_tx, generate_rx) = unbounded::<([i32; 3], TerrainSettings)>();
+    let (saved_tx, saved_rx) = unbounded();
+
+    let generators = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+        .saturating_sub(2)
+        .max(1);
+    for _ in 0..generators {
+        let generate_rx = generate_rx.clone();
+        let load_tx: Sender<Vec<([i32; 3], Chunk)>> = load_tx.clone();
+        thread::spawn(move || {
+            for (pos, terrain) in generate_rx {
+                // empty chunks are sent too, so they count as arrived
+                let chunk = generate_chunk(pos, &terrain);
+                if load_tx.send(vec![(pos, chunk)]).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    commands.insert_resource(ChunkStreamer {
+        from_save: store.has_saved_world(),
+        loaded: HashSet::new(),
+        arrived: HashSet::new(),
+        unloading: HashSet::new(),
+        load_tx,
+        load_rx,
+        found_tx,
+        found_rx,
+        generate_tx,
+        saved_tx,
+        saved_rx,
+    });
+}
+
+fn camera_chunk(cam_pos: Vec3, origin: &FloatingOrigin) -> [i32; 3] {
+    let world = origin.to_world(cam_pos);
+    let size = C_SIZE as f32;
+    [
+        (world.x / size).floor() as i32,
+        (world.y / size).floor() as i32,
+        (world.z / size).floor() as i32,
+    ]
+}
+
+pub fn stream_chunks(
+    mut streamer: ResMut<ChunkStreamer>,
+    settings: Res<StreamingSettings>,
+    origin: Res<FloatingOrigin>,
+    io: Res<RegionIo>,
+    cam_query: Query<&GlobalTransform, (With<PCamera>, Without<Player>)>,
+) {
+    let center = camera_chunk(cam_query.single().translation(), &origin);
+    let radius = settings.load_radius;
+
+    let mut requests = Vec::new();
+    for cx in -radius..=radius {
+        for cy in -radius..=radius {
+            for cz in -radius..=radius {
+                if cx * cx + cy * cy + cz * cz > radius * radius {
+                    continue;
+                }
+                let pos = [center[0] + cx, center[1] + cy, center[2] + cz];
+                if streamer.unloading.contains(&pos) || !streamer.loaded.insert(pos) {
+                    continue;
+                }
+                requests.push(pos);
+            }
+        }
+    }
+
+    if !requests.is_empty() {
+        io.load(requests, streamer.found_tx.clone());
+    }
+}
+
+pub fn receive_chunks(
+    mut streamer: ResMut<ChunkStreamer>,
+    world: Res<VoxWorld>,
+    terrain: Res<TerrainSettings>,
+    mut event_writer: EventWriter<GenerateOctreeEvent>,
+) {
+    for _ in 0..streamer.saved_rx.len() {
+        if let Ok(saved) = streamer.saved_rx.try_recv() {
+            for pos in saved {
+                streamer.unloading.remove(&pos);
+            }
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for _ in 0..streamer.found_rx.len() {
+        if let Ok(found) = streamer.found_rx.try_recv() {
+            for (pos, chunk) in found {
+                match chunk {
+                    Some(chunk) => chunks.push((pos, chunk)),
+                    None => {
+                        let _ = streamer.generate_tx.send((pos, terrain.clone()));
+                    }
+                }
+            }
+        }
+    }
+    for _ in 0..streamer.load_rx.len() {
+        if let Ok(generated) = streamer.load_rx.try_recv() {
+            chunks.extend(generated);
+        }
+    }
+    if chunks.is_empty() {
+        return;
+    }
+
+    let mut world = world.world.write().unwrap();
+    let mut inserted = false;
+    for (pos, chunk) in chunks {
+        // unloaded again while it was on its way
+        if !streamer.loaded.contains(&pos) {
+            continue;
+        }
+        streamer.arrived.insert(pos);
+        // the scene may already have put something here
+        if !chunk.is_empty() && world.get(pos).is_none() {
+            world.insert(pos, chunk);
+            inserted = true;
+        }
+    }
+    if inserted {
+        event_writer.send(GenerateOctreeEvent);
+    }
+}
+
+pub fn unload_chunks(
+    mut streamer: ResMut<ChunkStreamer>,
+    settings: Res<StreamingSettings>,
+    origin: Res<FloatingOrigin>,
+    world: Res<VoxWorld>,
+    io: Res<RegionIo>,
+    cam_query: Query<&GlobalTransform, (With<PCamera>, Without<Player>)>,
+) {
+    let center = camera_chunk(cam_query.single().translation(), &origin);
+    let radius = settings.unload_radius;
+    let far = |pos: &[i32; 3]| {
+        let d = [pos[0] - center[0], pos[1] - center[1], pos[2] - center[2]];
+        d[0] * d[0] + d[1] * d[1] + d[2] * d[2] > radius * radius
+    };
+
+    streamer.loaded.retain(|pos| !far(pos));
+    streamer.arrived.retain(|pos| !far(pos));
+
+    let mut to_save = Vec::new();
+    {
+        let mut chunks = world.world.write().unwrap();
+        let mut dirty = world.dirty.lock().unwrap();
+        let far_chunks: Vec<[i32; 3]> = chunks
+            .iter()
+            .map(|(pos, _)| pos)
+            .filter(|pos| far(pos))
+            .collect();
+        for pos in far_chunks {
+            let chunk = chunks.remove(pos).unwrap();
+            // untouched chunks are already on disk or generated the same way again
+            if dirty.remove(&pos) {
+                to_save.push((pos, chunk));
+            }
+        }
+    }
+
+    if to_save.is_empty() {
+        return;
+    }
+
+    streamer
+        .unloading
+        .extend(to_save.iter().map(|(pos, _)| *pos));
+    io.save(to_save, Some(streamer.saved_tx.clone()));
+}
+
+/// Shifts the floating origin by whole chunks once the player strays too far
+/// from it, moving everything that lives in local coordinates along with it.
+pub fn recenter_origin(
+    settings: Res<StreamingSettings>,
+    mut origin: ResMut<FloatingOrigin>,
+    mut player_query: Query<&mut Transform, With<Player>>,
+    mut vox_entities: Query<&mut VoxelEntity>,
+    mut particles: ResMut<Particles>,
+    mut event_writer: EventWriter<GenerateOctreeEvent>,
+) {
+    let mut player = player_query.single_mut();
+    if player.translation.length() < settings.recenter_distance {
+        return;
+    }
+
+    let size = C_SIZE as f32;
+    let shift = [
+        (player.translation.x / size).round() as i32 * C_SIZE as i32,
+        (player.translation.y / size).round() as i32 * C_SIZE as i32,
+        (player.translation.z / size).round() as i32 * C_SIZE as i32,
+    ];
+    let offset = Vec3::new(shift[0] as f32, shift[1] as f32, shift[2] as f32);
+
+    origin.origin = [
+        origin.origin[0] + shift[0],
+        origin.origin[1] + shift[1],
+        origin.origin[2] + shift[2],
+    ];
+    player.translation -= offset;
+    for mut vox_entity in vox_entities.iter_mut() {
+        vox_entity.transform.translation -= offset;
+    }
+    for particle in particles.particles.iter_mut() {
+        particle.position -= offset;
+    }
+
+    info!("moved floating origin to {:?}", origin.origin);
+    event_writer.send(GenerateOctreeEvent);
+}
@@ -0,0 +1,153 @@
+use bevy::{math::Affine3A, prelude::*};
+use dot_vox::DotVoxData;
+
+use crate::{
+    load_error::WorldLoadError,
+    world_generator::{
+        process_scene_node, scene_frame_count, world_position, ChunkMap, EditSource, StorageVoxel,
+        VoxImportOptions, VoxWorld, WorldData,
+    },
+};
+
+pub const DEFAULT_FPS: f32 = 10.0;
+
+/// Animation track of a multi-frame `.vox` scene. The world holds the current
+/// frame, `steps[k]` are the edits that turn frame `k` into the next one.
+#[derive(Component, Clone)]
+pub struct VoxAnimation {
+    steps: Vec<Vec<([i32; 3], Option<StorageVoxel>)>>,
+    pub fps: f32,
+    pub playing: bool,
+    current: usize,
+    timer: f32,
+}
+
+impl VoxAnimation {
+    /// Evaluates every frame of the scene and stores the differences between
+    /// them. Returns `None` for a scene with a single frame. `vox_data` must
+    /// have passed `check_scene`.
+    pub fn from_scene(
+        vox_data: &DotVoxData,
+        palette: &[StorageVoxel],
+        transform: Affine3A,
+        options: &VoxImportOptions,
+    ) -> Result<Option<Self>, WorldLoadError> {
+        let count = scene_frame_count(&vox_data.scenes);
+        if count < 2 {
+            return Ok(None);
+        }
+
+        let frames = (0..count)
+            .map(|frame| {
+                let mut world = WorldData::default();
+                process_scene_node(0, vox_data, palette, transform, frame, options, &mut world)?;
+                Ok(world.data)
+            })
+            .collect::<Result<Vec<ChunkMap>, WorldLoadError>>()?;
+
+        let steps = (0..frames.len())
+            .map(|k| frame_diff(&frames[k], &frames[(k + 1) % frames.len()]))
+            .collect();
+
+        Ok(Some(VoxAnimation {
+            steps,
+            fps: DEFAULT_FPS,
+            playing: true,
+            current: 0,
+            timer: 0.0,
+        }))
+    }
+
+    /// Every position some frame puts a voxel at. Together with the frame
+    /// that is in the world these are all voxels the animation can place.
+    pub fn positions(self: &Self) -> impl Iterator<Item = [i32; 3]> + '_ {
+        self.steps
+            .iter()
+            .flatten()
+            .filter(|(_, vox)| vox.is_some())
+            .map(|(pos, _)| *pos)
+    }
+}
+
+/// Edits that turn `from` into `to`.
+fn frame_diff(from: &ChunkMap, to: &ChunkMap) -> Vec<([i32; 3], Option<StorageVoxel>)> {
+    let mut edits = Vec::new();
+    for (chunk_pos, chunk) in from.iter() {
+        for (local, vox) in chunk.iter() {
+            let pos = world_position(chunk_pos, local);
+            let next = to.get_voxel(pos);
+            if next != Some(vox) {
+                edits.push((pos, next.cloned()));
+            }
+        }
+    }
+    for (chunk_pos, chunk) in to.iter() {
+        for (local, vox) in chunk.iter() {
+            let pos = world_position(chunk_pos, local);
+            if from.get_voxel(pos).is_none() {
+                edits.push((pos, Some(vox.clone())));
+            }
+        }
+    }
+    edits
+}
+
+/// Advances animations at their own frame rate. Frames go through the normal
+/// edit path so the octree is rebuilt and the chunks are saved.
+pub fn play_vox_animations(
+    time: Res<Time>,
+    world: Res<VoxWorld>,
+    mut animations: Query<&mut VoxAnimation>,
+) {
+    for mut animation in animations.iter_mut() {
+        if !animation.playing || animation.fps <= 0.0 {
+            continue;
+        }
+
+        animation.timer += time.delta_seconds();
+        let frame_time = 1.0 / animation.fps;
+        while animation.timer >= frame_time {
+            animation.timer -= frame_time;
+            let step = animation.steps[animation.current].clone();
+            world.apply_from(step, EditSource::Simulation);
+            animation.current = (animation.current + 1) % animation.steps.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(id: u8) -> StorageVoxel {
+        StorageVoxel {
+            id,
+            color: [id, id, id],
+            emission: 0.0,
+            material: 0,
+        }
+    }
+
+    #[test]
+    fn positions_cover_every_frame() {
+        let mut first = ChunkMap::default();
+        first.set_voxel([0, 0, 0], Some(voxel(1)));
+        first.set_voxel([1, 0, 0], Some(voxel(1)));
+        let mut second = ChunkMap::default();
+        second.set_voxel([1, 0, 0], Some(voxel(2)));
+        second.set_voxel([70, 0, 0], Some(voxel(2)));
+
+        let animation = VoxAnimation {
+            steps: vec![frame_diff(&first, &second), frame_diff(&second, &first)],
+            fps: DEFAULT_FPS,
+            playing: true,
+            current: 0,
+            timer: 0.0,
+        };
+        let mut positions: Vec<[i32; 3]> = animation.positions().collect();
+        positions.sort();
+        positions.dedup();
+        // [0, 0, 0] only comes back in the first frame, which is in the world
+        assert_eq!(positions, vec![[0, 0, 0], [1, 0, 0], [70, 0, 0]]);
+    }
+}
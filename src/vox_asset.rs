@@ -0,0 +1,166 @@
+use std::{sync::Arc, thread};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    math::Affine3A,
+    prelude::*,
+    utils::HashSet,
+};
+use dot_vox::DotVoxData;
+
+use crate::{
+    load_error::WorldLoadError,
+    material::MaterialTable,
+    world_generator::{build_vox_scene, check_scene, Channel, VoxImportOptions},
+};
+
+/// A parsed `.vox` file: models, palette, materials and scene graph, along
+/// with the options from its manifest.
+#[derive(Asset, TypePath)]
+pub struct VoxModel {
+    pub data: Arc<DotVoxData>,
+    pub options: VoxImportOptions,
+}
+
+/// Reads `.vox` files for the asset server. The scene graph is checked here
+/// so a broken file never reaches the world builder, and the manifest next to
+/// the file is read as a dependency so editing it reloads the model too.
+#[derive(Default)]
+pub struct VoxModelLoader;
+impl AssetLoader for VoxModelLoader {
+    type Asset = VoxModel;
+    type Settings = ();
+    type Error = WorldLoadError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<VoxModel, WorldLoadError> {
+        let path = load_context.path().display().to_string();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let data =
+            dot_vox::load_bytes(&bytes).map_err(|err| WorldLoadError::bad_file(&path, err))?;
+        check_scene(&path, &data)?;
+
+        let manifest = load_context.path().with_extension("ron");
+        let options = match load_context.read_asset_bytes(manifest.as_path()).await {
+            Ok(text) => match ron::de::from_bytes(&text) {
+                Ok(options) => options,
+                Err(err) => {
+                    info!("Error reading manifest {}: {}", manifest.display(), err);
+                    VoxImportOptions::default()
+                }
+            },
+            Err(_) => VoxImportOptions::default(),
+        };
+
+        Ok(VoxModel {
+            data: Arc::new(data),
+            options,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+/// A `.vox` scene placed in the world. It is built once the model has loaded
+/// and rebuilt whenever the file changes on disk.
+#[derive(Component)]
+pub struct VoxScene {
+    pub model: Handle<VoxModel>,
+    /// Placement in MagicaVoxel's Z-up space, in world coordinates since
+    /// the scene is baked into the chunks. Entities split off the scene are
+    /// moved into local coordinates when they are spawned.
+    pub transform: Affine3A,
+    /// Spawn the whole scene as one `VoxelEntity` with this name instead of
+    /// baking it into the world.
+    pub entity: Option<String>,
+    /// Voxels the last build put into the world, along with every voxel its
+    /// animation can place, cleared on a rebuild.
+    pub placed: Vec<[i32; 3]>,
+}
+impl VoxScene {
+    pub fn new(model: Handle<VoxModel>, transform: Affine3A) -> Self {
+        VoxScene {
+            model,
+            transform,
+            entity: None,
+            placed: Vec::new(),
+        }
+    }
+}
+
+/// Marks animations and entities spawned by a `VoxScene`, so a rebuild can
+/// replace them.
+#[derive(Component)]
+pub struct FromVoxScene(pub Entity);
+
+/// Builds every scene whose model finished loading or was modified, once per
+/// frame however many events a model got. The result goes through the same
+/// channel as the rest of the world.
+pub fn build_vox_scenes(
+    mut events: EventReader<AssetEvent<VoxModel>>,
+    models: Res<Assets<VoxModel>>,
+    scenes: Query<(Entity, &VoxScene)>,
+    channel: Res<Channel>,
+    materials: Res<MaterialTable>,
+) {
+    // a model that finished loading and changed in the same frame is built
+    // only once
+    let ids: HashSet<AssetId<VoxModel>> = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    for id in ids {
+        let model = match models.get(id) {
+            Some(model) => model,
+            None => continue,
+        };
+
+        for (scene_entity, scene) in scenes.iter() {
+            if scene.model.id() != id {
+                continue;
+            }
+            let tx = channel.tx.clone();
+            let data = Arc::clone(&model.data);
+            let options = model.options.clone();
+            let transform = scene.transform;
+            let entity = scene.entity.clone();
+            let materials = materials.clone();
+            let name = scene
+                .model
+                .path()
+                .map(|path| path.path().display().to_string())
+                .unwrap_or_else(|| "vox scene".to_string());
+            thread::spawn(move || {
+                match build_vox_scene(
+                    &name,
+                    &data,
+                    &options,
+                    transform,
+                    entity.as_deref(),
+                    &materials,
+                ) {
+                    Ok(mut world) => {
+                        if world.clipped > 0 {
+                            info!("Clipped {} voxels while loading {}", world.clipped, name);
+                        }
+                        world.scene = Some(scene_entity);
+                        if let Err(err) = tx.send(world) {
+                            info!("Error sending finished scene: {}", err);
+                        }
+                    }
+                    Err(err) => info!("Error loading {}: {}", name, err),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    player_controller::PCamera,
+    streaming::FloatingOrigin,
+    world_generator::{get_vox_color, StorageVoxel, VoxWorld, VoxelEntity},
+};
+
+/// Largest model MagicaVoxel accepts along each axis, bigger exports are split
+/// into several models.
+pub const MAX_MODEL_SIZE: i32 = 256;
+const VOX_VERSION: i32 = 150;
+/// Palette index 0 is empty space, so only 255 colours fit.
+const MAX_PALETTE: usize = 255;
+
+#[derive(Resource, Clone)]
+pub struct ExportSettings {
+    /// Directory exports are written to, created when needed.
+    pub dir: PathBuf,
+    /// Half the width of the box around the camera that is exported.
+    pub radius: i32,
+    /// `gltf` or `obj`, the format of mesh exports.
+    pub mesh_format: String,
+}
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("Assets/exports"),
+            radius: 64,
+            mesh_format: "gltf".to_string(),
+        }
+    }
+}
+
+/// `F9` exports the box around the camera to a `.vox` file, `Shift+F9` the
+/// `VoxelEntity` closest to the camera.
+pub fn export_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<ExportSettings>,
+    world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    camera: Query<&GlobalTransform, With<PCamera>>,
+    entities: Query<&VoxelEntity>,
+) {
+    if !keys.just_pressed(KeyCode::F9) {
+        return;
+    }
+    let camera = match camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    if let Err(err) = fs::create_dir_all(&settings.dir) {
+        info!("Error creating {}: {}", settings.dir.display(), err);
+        return;
+    }
+
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let result = if shift {
+        let nearest = entities.iter().min_by(|a, b| {
+            let a = a
+                .transform
+                .translation
+                .distance_squared(camera.translation());
+            let b = b
+                .transform
+                .translation
+                .distance_squared(camera.translation());
+            a.total_cmp(&b)
+        });
+        let entity = match nearest {
+            Some(entity) => entity,
+            None => return,
+        };
+        let path = settings.dir.join(format!("{}.vox", entity.name));
+        export_entity(entity, &path).map(|_| path)
+    } else {
+        let center = origin.to_world(camera.translation()).floor().as_ivec3();
+        let min = (center - settings.radius).to_array();
+        let max = (center + settings.radius).to_array();
+        let path = settings
+            .dir
+            .join(format!("region_{}_{}_{}.vox", center.x, center.y, center.z));
+        export_region(&world, min, max, &path).map(|_| path)
+    };
+    match result {
+        Ok(path) => info!("Exported {}", path.display()),
+        Err(err) => info!("Error exporting: {}", err),
+    }
+}
+
+/// Writes the voxels of `VoxWorld` within `min` and `max` (inclusive, world
+/// coordinates) to a `.vox` file. Only loaded chunks are exported.
+pub fn export_region(
+    world: &VoxWorld,
+    min: [i32; 3],
+    max: [i32; 3],
+    path: &Path,
+) -> io::Result<()> {
+    write_vox(&world.voxels_in(min, max), path)
+}
+
+/// Writes a `VoxelEntity` to a `.vox` file, relative to its transform.
+pub fn export_entity(entity: &VoxelEntity, path: &Path) -> io::Result<()> {
+    write_vox(&entity.voxels, path)
+}
+
+/// Writes voxels given in Y-up world coordinates as a MagicaVoxel scene. The
+/// voxels are split into models of at most `MAX_MODEL_SIZE`³, each placed by
+/// its own transform node so importing the file puts every voxel back where
+/// it came from.
+pub fn write_vox(voxels: &[([i32; 3], StorageVoxel)], path: &Path) -> io::Result<()> {
+    if voxels.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nothing to export",
+        ));
+    }
+
+    let palette = VoxPalette::build(voxels.iter().map(|(_, vox)| vox));
+
+    // MagicaVoxel is Z-up
+    let positions: Vec<[i32; 3]> = voxels.iter().map(|(p, _)| [p[0], p[2], p[1]]).collect();
+    let mut min = positions[0];
+    for p in positions.iter() {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+        }
+    }
+
+    let mut models: HashMap<[i32; 3], Vec<[u8; 4]>> = HashMap::new();
+    for (p, (_, vox)) in positions.iter().zip(voxels.iter()) {
+        let offset = [p[0] - min[0], p[1] - min[1], p[2] - min[2]];
+        let tile = offset.map(|o| o / MAX_MODEL_SIZE);
+        models.entry(tile).or_default().push([
+            (offset[0] % MAX_MODEL_SIZE) as u8,
+            (offset[1] % MAX_MODEL_SIZE) as u8,
+            (offset[2] % MAX_MODEL_SIZE) as u8,
+            palette.index(vox),
+        ]);
+    }
+    let mut models: Vec<([i32; 3], Vec<[u8; 4]>)> = models.into_iter().collect();
+    models.sort_by_key(|(tile, _)| *tile);
+
+    let mut children = Vec::new();
+    for (_, model) in models.iter() {
+        let size = model_size(model);
+        let mut content = Vec::new();
+        for s in size {
+            content.extend(s.to_le_bytes());
+        }
+        write_chunk(&mut children, b"SIZE", &content, &[]);
+
+        let mut content = Vec::new();
+        content.extend((model.len() as i32).to_le_bytes());
+        for vox in model.iter() {
+            content.extend(vox);
+        }
+        write_chunk(&mut children, b"XYZI", &content, &[]);
+    }
+
+    // root transform -> group -> one transform and shape per model
+    write_transform(&mut children, 0, 1, [0; 3]);
+    let shape_nodes: Vec<i32> = (0..models.len() as i32).map(|m| 2 + m * 2).collect();
+    let mut group = Vec::new();
+    group.extend(1i32.to_le_bytes());
+    write_dict(&mut group, &[]);
+    group.extend((shape_nodes.len() as i32).to_le_bytes());
+    for id in shape_nodes.iter() {
+        group.extend(id.to_le_bytes());
+    }
+    write_chunk(&mut children, b"nGRP", &group, &[]);
+
+    for (model_id, (tile, model)) in models.iter().enumerate() {
+        let size = model_size(model);
+        // the importer centres models on their transform, rounding down
+        let translation = [0, 1, 2].map(|i| min[i] + tile[i] * MAX_MODEL_SIZE + size[i] / 2);
+        let transform_id = shape_nodes[model_id];
+        write_transform(&mut children, transform_id, transform_id + 1, translation);
+
+        let mut shape = Vec::new();
+        shape.extend((transform_id + 1).to_le_bytes());
+        write_dict(&mut shape, &[]);
+        shape.extend(1i32.to_le_bytes());
+        shape.extend((model_id as i32).to_le_bytes());
+        write_dict(&mut shape, &[]);
+        write_chunk(&mut children, b"nSHP", &shape, &[]);
+    }
+
+    let mut rgba = Vec::with_capacity(256 * 4);
+    for i in 0..256 {
+        let color = palette.colors.get(i).map(|vox| get_vox_color(vox.color));
+        match color {
+            Some(c) => rgba.extend([c.r, c.g, c.b, c.a]),
+            None => rgba.extend([0, 0, 0, 255]),
+        }
+    }
+    write_chunk(&mut children, b"RGBA", &rgba, &[]);
+
+    // material ids match the 1 based palette index
+    for (i, vox) in palette.colors.iter().enumerate() {
+        if vox.emission <= 0.0 {
+            continue;
+        }
+        let mut matl = Vec::new();
+        matl.extend((i as i32 + 1).to_le_bytes());
+        write_dict(
+            &mut matl,
+            &[
+                ("_type", "_emit".to_string()),
+                ("_emit", vox.emission.to_string()),
+            ],
+        );
+        write_chunk(&mut children, b"MATL", &matl, &[]);
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"VOX ")?;
+    file.write_all(&VOX_VERSION.to_le_bytes())?;
+    let mut main = Vec::new();
+    write_chunk(&mut main, b"MAIN", &[], &children);
+    file.write_all(&main)?;
+    file.flush()
+}
+
+/// Up to 255 distinct voxels. Once it is full, further voxels use the entry
+/// with the closest colour.
+struct VoxPalette {
+    colors: Vec<StorageVoxel>,
+}
+impl VoxPalette {
+    fn build<'a>(voxels: impl Iterator<Item = &'a StorageVoxel>) -> Self {
+        let mut colors: Vec<StorageVoxel> = Vec::new();
+        for vox in voxels {
+            if colors.len() < MAX_PALETTE && !colors.contains(vox) {
+                colors.push(vox.clone());
+            }
+        }
+        VoxPalette { colors }
+    }
+
+    /// 1 based index as stored in `XYZI`.
+    fn index(self: &Self, vox: &StorageVoxel) -> u8 {
+        if let Some(i) = self.colors.iter().position(|c| c == vox) {
+            return i as u8 + 1;
+        }
+        let distance = |c: &StorageVoxel| {
+            (0..3)
+                .map(|i| (c.color[i] as i32 - vox.color[i] as i32).pow(2))
+                .sum::<i32>()
+        };
+        let (i, _) = self
+            .colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| distance(c))
+            .unwrap();
+        i as u8 + 1
+    }
+}
+
+fn model_size(model: &[[u8; 4]]) -> [i32; 3] {
+    let mut size = [1; 3];
+    for vox in model.iter() {
+        for i in 0..3 {
+            size[i] = size[i].max(vox[i] as i32 + 1);
+        }
+    }
+    size
+}
+
+fn write_transform(out: &mut Vec<u8>, node_id: i32, child_id: i32, translation: [i32; 3]) {
+    let mut content = Vec::new();
+    content.extend(node_id.to_le_bytes());
+    write_dict(&mut content, &[]);
+    content.extend(child_id.to_le_bytes());
+    // reserved id, layer id and frame count
+    content.extend((-1i32).to_le_bytes());
+    content.extend(0i32.to_le_bytes());
+    content.extend(1i32.to_le_bytes());
+    write_dict(
+        &mut content,
+        &[(
+            "_t",
+            format!("{} {} {}", translation[0], translation[1], translation[2]),
+        )],
+    );
+    write_chunk(out, b"nTRN", &content, &[]);
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, String)]) {
+    out.extend((entries.len() as i32).to_le_bytes());
+    for (key, value) in entries.iter() {
+        write_string(out, key);
+        write_string(out, value);
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as i32).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend(id);
+    out.extend((content.len() as i32).to_le_bytes());
+    out.extend((children.len() as i32).to_le_bytes());
+    out.extend(content);
+    out.extend(children);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Affine3A;
+
+    use super::*;
+    use crate::{
+        material::{MaterialKind, MaterialTable},
+        world_generator::{
+            build_vox_scene, color_voxel, world_position, VoxImportOptions, WorldData,
+        },
+    };
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{}.vox", name, std::process::id()))
+    }
+
+    /// Writes `voxels` and builds the file again the way scenes are loaded.
+    fn round_trip(name: &str, voxels: &[([i32; 3], StorageVoxel)]) -> (WorldData, MaterialTable) {
+        let path = temp_file(name);
+        write_vox(voxels, &path).unwrap();
+        let vox_data = dot_vox::load_bytes(&fs::read(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(path);
+
+        let materials = MaterialTable::default();
+        let world = build_vox_scene(
+            name,
+            &vox_data,
+            &VoxImportOptions::default(),
+            Affine3A::IDENTITY,
+            None,
+            &materials,
+        )
+        .unwrap();
+        (world, materials)
+    }
+
+    fn world_voxels(world: &WorldData) -> Vec<([i32; 3], StorageVoxel)> {
+        let mut voxels = Vec::new();
+        for (pos, chunk) in world.data.iter() {
+            for (local, vox) in chunk.iter() {
+                voxels.push((world_position(pos, local), vox.clone()));
+            }
+        }
+        voxels.sort_by_key(|(pos, _)| *pos);
+        voxels
+    }
+
+    fn voxel(r: u8, g: u8, b: u8) -> StorageVoxel {
+        color_voxel(dot_vox::Color { r, g, b, a: 255 }, 0.0)
+    }
+
+    #[test]
+    fn exported_voxels_import_at_the_same_positions() {
+        // spans more than one model along x, and sits at negative coordinates
+        let voxels = vec![
+            ([-3, 5, -7], voxel(200, 10, 10)),
+            ([-2, 5, -7], voxel(10, 200, 10)),
+            ([-3, 9, -6], voxel(10, 10, 200)),
+            ([300, 5, -7], voxel(200, 10, 10)),
+        ];
+        let (world, _) = round_trip("positions", &voxels);
+        let imported = world_voxels(&world);
+
+        let mut expected = voxels.clone();
+        expected.sort_by_key(|(pos, _)| *pos);
+        assert_eq!(imported.len(), expected.len());
+        for ((pos, vox), (expected_pos, expected_vox)) in imported.iter().zip(expected.iter()) {
+            assert_eq!(pos, expected_pos);
+            assert_eq!(vox.color, expected_vox.color);
+        }
+    }
+
+    #[test]
+    fn palette_is_capped_at_255_entries() {
+        let voxels: Vec<([i32; 3], StorageVoxel)> = (0..300)
+            .map(|i| ([i, 0, 0], voxel((i % 256) as u8, (i / 256) as u8 * 100, 7)))
+            .collect();
+        let (world, _) = round_trip("palette", &voxels);
+        let imported = world_voxels(&world);
+        assert_eq!(imported.len(), 300);
+
+        // the first 255 colours are kept, the rest use the closest of them
+        for (pos, vox) in imported.iter().take(MAX_PALETTE) {
+            assert_eq!(vox.color, voxels[pos[0] as usize].1.color);
+        }
+        let mut colors: Vec<[u8; 3]> = imported.iter().map(|(_, vox)| vox.color).collect();
+        colors.sort();
+        colors.dedup();
+        assert_eq!(colors.len(), MAX_PALETTE);
+    }
+
+    #[test]
+    fn emissive_voxels_get_an_emit_material() {
+        let glow = color_voxel(
+            dot_vox::Color {
+                r: 255,
+                g: 200,
+                b: 0,
+                a: 255,
+            },
+            2.5,
+        );
+        let voxels = vec![([0, 0, 0], voxel(50, 50, 50)), ([1, 0, 0], glow)];
+        let (world, materials) = round_trip("emission", &voxels);
+        let imported = world_voxels(&world);
+
+        assert_eq!(imported[0].1.emission, 0.0);
+        assert_eq!(
+            materials.get(imported[0].1.material).kind,
+            MaterialKind::Diffuse
+        );
+        assert_eq!(imported[1].1.emission, 2.5);
+        let material = materials.get(imported[1].1.material);
+        assert_eq!(material.kind, MaterialKind::Emit);
+        assert_eq!(material.emission, 2.5);
+    }
+
+    #[test]
+    fn entities_export_relative_to_their_transform() {
+        let entity = VoxelEntity {
+            name: "crate".to_string(),
+            transform: Transform::from_xyz(1000.0, 0.0, 0.0),
+            voxels: vec![([0, 0, 0], voxel(1, 2, 3)), ([0, 1, 0], voxel(1, 2, 3))],
+        };
+        let path = temp_file("entity");
+        export_entity(&entity, &path).unwrap();
+        let vox_data = dot_vox::load_bytes(&fs::read(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(vox_data.models.len(), 1);
+        // MagicaVoxel is Z-up, so the stack is along z
+        let size = vox_data.models[0].size;
+        assert_eq!((size.x, size.y, size.z), (1, 1, 2));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    fluid::fluid_of,
+    material::MaterialTable,
+    rigid_body::RigidBody,
+    streaming::{ChunkStreamer, FloatingOrigin},
+    world_generator::{
+        chunk_of, chunks_near, world_position, EditSource, StorageVoxel, VoxWorld, VoxelEntity,
+        VoxelsChanged,
+    },
+};
+
+#[derive(Resource, Clone)]
+pub struct PhysicsSettings {
+    pub enabled: bool,
+    /// Steps per second of the falling voxels.
+    pub tick_rate: f32,
+    /// Chunks stepped per tick, the rest wait for the next one.
+    pub max_chunks: usize,
+    /// Turn pieces that lost their connection to the rest of the world into
+    /// `VoxelEntity` debris after edits.
+    pub structural_integrity: bool,
+    /// A connected piece with more voxels than this counts as anchored.
+    pub max_island: usize,
+    /// Edits spanning a larger box than this are not checked.
+    pub max_check_volume: i64,
+}
+impl Default for PhysicsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            tick_rate: 20.0,
+            max_chunks: 64,
+            structural_integrity: true,
+            max_island: 4096,
+            max_check_volume: 32 * 32 * 32,
+        }
+    }
+}
+
+/// Chunks that may hold unsupported granular voxels, woken the same way as
+/// `ActiveFluids`.
+#[derive(Resource, Default)]
+pub struct ActiveGranular {
+    pub chunks: HashSet<[i32; 3]>,
+    seen: HashSet<[i32; 3]>,
+    timer: f32,
+    tick: u32,
+}
+
+pub fn wake_granular(
+    world: Res<VoxWorld>,
+    mut changes: EventReader<VoxelsChanged>,
+    mut active: ResMut<ActiveGranular>,
+) {
+    let chunks = world.world.read().unwrap();
+    let active = &mut *active;
+    active.seen.retain(|pos| chunks.get(*pos).is_some());
+    for (pos, _) in chunks.iter() {
+        if active.seen.insert(pos) {
+            active.chunks.insert(pos);
+        }
+    }
+    for change in changes.read() {
+        active.chunks.extend(chunks_near(change, &chunks));
+    }
+}
+
+/// Granular voxels fall straight down when the voxel below is empty, slide
+/// down a side when it is not, and sink through fluids.
+pub fn simulate_granular(
+    time: Res<Time>,
+    settings: Res<PhysicsSettings>,
+    world: Res<VoxWorld>,
+    streamer: Res<ChunkStreamer>,
+    materials: Res<MaterialTable>,
+    mut active: ResMut<ActiveGranular>,
+) {
+    if !settings.enabled || settings.tick_rate <= 0.0 {
+        return;
+    }
+    active.timer += time.delta_seconds();
+    if active.timer < 1.0 / settings.tick_rate {
+        return;
+    }
+    active.timer = 0.0;
+    active.tick = active.tick.wrapping_add(1);
+    let tick = active.tick;
+
+    let chunks: Vec<[i32; 3]> = active
+        .chunks
+        .iter()
+        .take(settings.max_chunks)
+        .copied()
+        .collect();
+    for pos in chunks.iter() {
+        active.chunks.remove(pos);
+    }
+
+    let granular = materials.granular();
+    let is_granular = |vox: &StorageVoxel| {
+        granular
+            .get(vox.material as usize)
+            .copied()
+            .unwrap_or(false)
+    };
+
+    let mut cells = Vec::new();
+    {
+        let world = world.world.read().unwrap();
+        for chunk_pos in chunks.iter() {
+            if let Some(chunk) = world.get(*chunk_pos) {
+                for (local, vox) in chunk.iter() {
+                    if is_granular(vox) {
+                        cells.push(world_position(*chunk_pos, local));
+                    }
+                }
+            }
+        }
+    }
+    if cells.is_empty() {
+        return;
+    }
+    // bottom up, so a falling column moves as one
+    cells.sort_by_key(|pos| pos[1]);
+
+    let mut changed: HashMap<[i32; 3], Option<StorageVoxel>> = HashMap::new();
+    let get = |changed: &HashMap<[i32; 3], Option<StorageVoxel>>, pos: [i32; 3]| match changed
+        .get(&pos)
+    {
+        Some(vox) => vox.clone(),
+        None => world.get_voxel(pos),
+    };
+    // empty and arrived, granular voxels never leave the chunks in memory
+    let is_free = |changed: &HashMap<[i32; 3], Option<StorageVoxel>>, pos: [i32; 3]| {
+        get(changed, pos).is_none() && streamer.has_arrived(chunk_of(pos).0)
+    };
+
+    let sides = [[1, 0], [0, 1], [-1, 0], [0, -1]];
+    for pos in cells {
+        let vox = match get(&changed, pos) {
+            Some(vox) if is_granular(&vox) => vox,
+            _ => continue,
+        };
+        let below = [pos[0], pos[1] - 1, pos[2]];
+
+        let target = if is_free(&changed, below) {
+            Some(below)
+        } else {
+            (0..4).find_map(|k| {
+                let [dx, dz] = sides[(k + tick as usize) % 4];
+                let side = [pos[0] + dx, pos[1], pos[2] + dz];
+                let side_below = [side[0], side[1] - 1, side[2]];
+                (is_free(&changed, side) && is_free(&changed, side_below)).then_some(side_below)
+            })
+        };
+        match target {
+            Some(target) => {
+                changed.insert(target, Some(vox));
+                changed.insert(pos, None);
+            }
+            // swap places with fluid below
+            None => {
+                if let Some(fluid) = get(&changed, below).filter(|b| fluid_of(b).is_some()) {
+                    changed.insert(below, Some(vox));
+                    changed.insert(pos, Some(fluid));
+                }
+            }
+        }
+    }
+
+    world.apply_from(changed.into_iter().collect(), EditSource::Simulation);
+}
+
+/// After user edits, flood fills the solid voxels around them. A piece that
+/// is smaller than `max_island` and never reaches a chunk that has not
+/// arrived is floating, so it is taken out of the world and spawned as a
+/// `VoxelEntity` with a `RigidBody`. Simulated edits are not checked, so the
+/// floating parts of animated scenes stay where they are. Fluids and granular
+/// voxels are never part of a piece, but a piece resting on granular voxels
+/// counts as supported until the next user edit near it.
+pub fn detach_islands(
+    mut commands: Commands,
+    settings: Res<PhysicsSettings>,
+    world: Res<VoxWorld>,
+    streamer: Res<ChunkStreamer>,
+    materials: Res<MaterialTable>,
+    origin: Res<FloatingOrigin>,
+    mut changes: EventReader<VoxelsChanged>,
+) {
+    if !settings.enabled || !settings.structural_integrity {
+        changes.clear();
+        return;
+    }
+
+    let granular = materials.granular();
+    let mut visited: HashSet<[i32; 3]> = HashSet::new();
+    let mut islands: Vec<Vec<([i32; 3], StorageVoxel)>> = Vec::new();
+    {
+        let chunks = world.world.read().unwrap();
+        let is_granular = |vox: &StorageVoxel| {
+            granular
+                .get(vox.material as usize)
+                .copied()
+                .unwrap_or(false)
+        };
+        let is_solid = |pos: [i32; 3]| match chunks.get_voxel(pos) {
+            Some(vox) => fluid_of(vox).is_none() && !is_granular(vox),
+            None => false,
+        };
+        let is_support = |pos: [i32; 3]| match chunks.get_voxel(pos) {
+            Some(vox) => is_granular(vox),
+            None => false,
+        };
+
+        for change in changes.read() {
+            if change.source != EditSource::User {
+                continue;
+            }
+            let volume = (0..3)
+                .map(|i| (change.max[i] - change.min[i] + 3) as i64)
+                .product::<i64>();
+            if volume > settings.max_check_volume {
+                continue;
+            }
+
+            for x in change.min[0] - 1..=change.max[0] + 1 {
+                for y in change.min[1] - 1..=change.max[1] + 1 {
+                    for z in change.min[2] - 1..=change.max[2] + 1 {
+                        let seed = [x, y, z];
+                        if visited.contains(&seed) || !is_solid(seed) {
+                            continue;
+                        }
+
+                        let mut piece = vec![seed];
+                        let mut queue = VecDeque::from([seed]);
+                        let mut anchored = false;
+                        visited.insert(seed);
+                        while let Some(pos) = queue.pop_front() {
+                            if piece.len() > settings.max_island
+                                || !streamer.has_arrived(chunk_of(pos).0)
+                            {
+                                anchored = true;
+                                break;
+                            }
+                            for d in [
+                                [1, 0, 0],
+                                [-1, 0, 0],
+                                [0, 1, 0],
+                                [0, -1, 0],
+                                [0, 0, 1],
+                                [0, 0, -1],
+                            ] {
+                                let next = [pos[0] + d[0], pos[1] + d[1], pos[2] + d[2]];
+                                if is_support(next) {
+                                    anchored = true;
+                                } else if !visited.contains(&next) && is_solid(next) {
+                                    visited.insert(next);
+                                    piece.push(next);
+                                    queue.push_back(next);
+                                }
+                            }
+                            if anchored {
+                                break;
+                            }
+                        }
+                        // an anchored piece stays marked as visited, so the
+                        // rest of it is not filled again from another seed
+
+                        if !anchored {
+                            islands.push(
+                                piece
+                                    .into_iter()
+                                    .filter_map(|pos| Some((pos, chunks.get_voxel(pos)?.clone())))
+                                    .collect(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for island in islands {
+        let mut min = island[0].0;
+        for (pos, _) in island.iter() {
+            for i in 0..3 {
+                min[i] = min[i].min(pos[i]);
+            }
+        }
+        world.apply_from(
+            island.iter().map(|(pos, _)| (*pos, None)).collect(),
+            EditSource::Simulation,
+        );
+        let debris = VoxelEntity {
+            name: "debris".to_string(),
+            transform: Transform::from_translation(origin.to_local(min)),
+            voxels: island
+                .into_iter()
+                .map(|(pos, vox)| ([pos[0] - min[0], pos[1] - min[1], pos[2] - min[2]], vox))
+                .collect(),
+        };
+        let body = RigidBody::from_entity(&debris);
+        commands.spawn((debris, body));
+    }
+}
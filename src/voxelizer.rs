@@ -0,0 +1,537 @@
+use std::{
+    collections::VecDeque,
+    io,
+    path::{Path, PathBuf},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    region::invalid_data,
+    world_generator::{color_voxel, insert_voxel_list, StorageVoxel, WorldData},
+};
+
+/// Colour used when a mesh has no vertex colours, textures or material.
+const DEFAULT_COLOR: [f32; 4] = [0.8, 0.8, 0.8, 1.0];
+/// Largest box along each axis that `VoxelizeMode::Solid` fills, the fill
+/// keeps a bit per voxel of the whole box.
+pub const MAX_SOLID_SIZE: i32 = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VoxelizeMode {
+    /// Only voxels that the triangles pass through.
+    Surface,
+    /// The surface plus everything it encloses. Meshes with holes leak and
+    /// come out as a surface.
+    Solid,
+}
+
+#[derive(Clone)]
+pub struct MeshTriangle {
+    pub positions: [Vec3; 3],
+    pub uvs: Option<[Vec2; 3]>,
+    /// Linear RGBA.
+    pub colors: Option<[Vec4; 3]>,
+    pub material: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct MeshMaterial {
+    /// Linear RGBA, multiplied with the texture.
+    pub base_color: Vec4,
+    pub texture: Option<usize>,
+}
+
+/// sRGB RGBA pixels, rows from the top.
+#[derive(Clone)]
+pub struct MeshTexture {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+impl MeshTexture {
+    /// Nearest sample with repeating UVs, returned as linear RGBA. The
+    /// texture must not be empty.
+    fn sample(self: &Self, uv: Vec2) -> Vec4 {
+        let x = (uv.x.rem_euclid(1.0) * self.width as f32) as u32;
+        let y = (uv.y.rem_euclid(1.0) * self.height as f32) as u32;
+        let p = self.pixels[(y.min(self.height - 1) * self.width + x.min(self.width - 1)) as usize];
+        Vec4::new(
+            srgb_to_linear(p[0]),
+            srgb_to_linear(p[1]),
+            srgb_to_linear(p[2]),
+            p[3] as f32 / 255.0,
+        )
+    }
+}
+
+/// Triangles of a mesh with everything needed to colour them.
+#[derive(Clone, Default)]
+pub struct VoxelMesh {
+    pub triangles: Vec<MeshTriangle>,
+    pub materials: Vec<MeshMaterial>,
+    pub textures: Vec<MeshTexture>,
+}
+impl VoxelMesh {
+    /// Loads an `.obj` or a `.gltf`/`.glb` file, picked by extension.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("obj") => load_obj(path),
+            Some("gltf") | Some("glb") => load_gltf(path),
+            _ => Err(invalid_data(&format!(
+                "{} is not an obj or gltf file",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Colour at barycentric coordinates `b` of a triangle. Vertex colours
+    /// win over the material, the texture is multiplied with the base colour.
+    fn color(self: &Self, triangle: &MeshTriangle, b: Vec3) -> Vec4 {
+        if let Some(colors) = &triangle.colors {
+            return colors[0] * b.x + colors[1] * b.y + colors[2] * b.z;
+        }
+        let material = match triangle.material.and_then(|m| self.materials.get(m)) {
+            Some(material) => material,
+            None => return Vec4::from_array(DEFAULT_COLOR),
+        };
+        let texture = material.texture.and_then(|t| self.textures.get(t));
+        match (texture, &triangle.uvs) {
+            // an empty image has nothing to sample
+            (Some(texture), Some(uvs)) if texture.width > 0 && texture.height > 0 => {
+                material.base_color * texture.sample(uvs[0] * b.x + uvs[1] * b.y + uvs[2] * b.z)
+            }
+            _ => material.base_color,
+        }
+    }
+}
+
+/// Turns a mesh into voxels of `voxel_size` world units after placing it with
+/// `transform`. Positions in the result are voxel coordinates.
+pub fn voxelize(
+    mesh: &VoxelMesh,
+    voxel_size: f32,
+    transform: Transform,
+    mode: VoxelizeMode,
+) -> io::Result<Vec<([i32; 3], StorageVoxel)>> {
+    let matrix = transform.compute_matrix();
+    let mut surface: HashMap<[i32; 3], StorageVoxel> = HashMap::new();
+
+    for triangle in mesh.triangles.iter() {
+        let p = triangle
+            .positions
+            .map(|p| matrix.transform_point3(p) / voxel_size);
+
+        // sample at most half a voxel apart so the surface has no holes
+        let longest = (p[1] - p[0])
+            .length()
+            .max((p[2] - p[1]).length())
+            .max((p[0] - p[2]).length());
+        let steps = (longest * 2.0).ceil().max(1.0) as u32;
+        for i in 0..=steps {
+            for j in 0..=steps - i {
+                let b = Vec3::new(
+                    i as f32 / steps as f32,
+                    j as f32 / steps as f32,
+                    (steps - i - j) as f32 / steps as f32,
+                );
+                let pos = p[0] * b.x + p[1] * b.y + p[2] * b.z;
+                let key = [
+                    pos.x.floor() as i32,
+                    pos.y.floor() as i32,
+                    pos.z.floor() as i32,
+                ];
+                if surface.contains_key(&key) {
+                    continue;
+                }
+                let color = mesh.color(triangle, b);
+                if color.w < 0.5 {
+                    continue;
+                }
+                surface.insert(key, mesh_voxel(color));
+            }
+        }
+    }
+
+    let mut voxels: Vec<([i32; 3], StorageVoxel)> = surface
+        .iter()
+        .map(|(pos, vox)| (*pos, vox.clone()))
+        .collect();
+    if mode == VoxelizeMode::Surface || surface.is_empty() {
+        return Ok(voxels);
+    }
+
+    let mut min = [i32::MAX; 3];
+    let mut max = [i32::MIN; 3];
+    for pos in surface.keys() {
+        for i in 0..3 {
+            min[i] = min[i].min(pos[i]);
+            max[i] = max[i].max(pos[i]);
+        }
+    }
+    if (0..3).any(|i| max[i] - min[i] + 3 > MAX_SOLID_SIZE) {
+        return Err(invalid_data(&format!(
+            "mesh is larger than {} voxels, too large to fill",
+            MAX_SOLID_SIZE
+        )));
+    }
+
+    voxels.extend(fill_interior(&surface, min, max));
+    Ok(voxels)
+}
+
+/// Voxelizes a mesh file and puts it into the world, `transform` is in world
+/// units and the voxels land on the world grid when `voxel_size` is 1.
+pub fn insert_mesh(
+    path: &Path,
+    voxel_size: f32,
+    transform: Transform,
+    mode: VoxelizeMode,
+    world: &mut WorldData,
+) -> io::Result<usize> {
+    let mesh = VoxelMesh::load(path)?;
+    let voxels = voxelize(&mesh, voxel_size, transform, mode)?;
+    insert_voxel_list(&voxels, [0; 3], world);
+    Ok(voxels.len())
+}
+
+/// Flood fills the outside of the surface inside a box one voxel larger than
+/// it. Whatever is left is inside, and takes the colour of the closest
+/// surface voxel before it along x.
+fn fill_interior(
+    surface: &HashMap<[i32; 3], StorageVoxel>,
+    min: [i32; 3],
+    max: [i32; 3],
+) -> Vec<([i32; 3], StorageVoxel)> {
+    let min = min.map(|m| m - 1);
+    let size = [0, 1, 2].map(|i| (max[i] + 1 - min[i] + 1) as usize);
+    let index = |p: [usize; 3]| (p[0] * size[1] + p[1]) * size[2] + p[2];
+
+    let mut solid = vec![0u64; (size[0] * size[1] * size[2] + 63) / 64];
+    let get = |bits: &Vec<u64>, i: usize| bits[i / 64] & (1 << (i % 64)) != 0;
+    let set = |bits: &mut Vec<u64>, i: usize| bits[i / 64] |= 1 << (i % 64);
+    for pos in surface.keys() {
+        set(
+            &mut solid,
+            index([0, 1, 2].map(|i| (pos[i] - min[i]) as usize)),
+        );
+    }
+
+    let mut outside = vec![0u64; solid.len()];
+    let mut queue = VecDeque::from([[0usize; 3]]);
+    set(&mut outside, 0);
+    while let Some(p) = queue.pop_front() {
+        for (axis, dir) in [(0, -1), (0, 1), (1, -1), (1, 1), (2, -1), (2, 1)] {
+            let n = p[axis] as i64 + dir;
+            if n < 0 || n >= size[axis] as i64 {
+                continue;
+            }
+            let mut next = p;
+            next[axis] = n as usize;
+            let i = index(next);
+            if !get(&solid, i) && !get(&outside, i) {
+                set(&mut outside, i);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let mut interior = Vec::new();
+    for y in 0..size[1] {
+        for z in 0..size[2] {
+            let mut last: Option<&StorageVoxel> = None;
+            for x in 0..size[0] {
+                let pos = [min[0] + x as i32, min[1] + y as i32, min[2] + z as i32];
+                if let Some(vox) = surface.get(&pos) {
+                    last = Some(vox);
+                } else if !get(&outside, index([x, y, z])) {
+                    if let Some(vox) = last {
+                        interior.push((pos, vox.clone()));
+                    }
+                }
+            }
+        }
+    }
+    interior
+}
+
+fn mesh_voxel(color: Vec4) -> StorageVoxel {
+    color_voxel(
+        dot_vox::Color {
+            r: linear_to_srgb(color.x),
+            g: linear_to_srgb(color.y),
+            b: linear_to_srgb(color.z),
+            a: 255,
+        },
+        0.0,
+    )
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    Color::srgb_u8(c, 0, 0).to_linear().red
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    (Color::linear_rgb(c.clamp(0.0, 1.0), 0.0, 0.0)
+        .to_srgba()
+        .red
+        * 255.0)
+        .round() as u8
+}
+
+fn load_obj(path: &Path) -> io::Result<VoxelMesh> {
+    let (models, materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)
+        .map_err(|err| invalid_data(&err.to_string()))?;
+    let materials = materials.unwrap_or_else(|err| {
+        info!("Error loading materials of {}: {}", path.display(), err);
+        Vec::new()
+    });
+
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    let mut mesh = VoxelMesh::default();
+    for material in materials.iter() {
+        let diffuse = material.diffuse.unwrap_or([1.0; 3]);
+        let texture = material
+            .diffuse_texture
+            .as_ref()
+            .and_then(|file| load_texture(&dir.join(file), &mut mesh));
+        mesh.materials.push(MeshMaterial {
+            base_color: Vec4::new(diffuse[0], diffuse[1], diffuse[2], 1.0),
+            texture,
+        });
+    }
+
+    for model in models.iter() {
+        let m = &model.mesh;
+        let position = |i: usize| {
+            Vec3::new(
+                m.positions[i * 3],
+                m.positions[i * 3 + 1],
+                m.positions[i * 3 + 2],
+            )
+        };
+        // obj texture coordinates start at the bottom
+        let uv = |i: usize| Vec2::new(m.texcoords[i * 2], 1.0 - m.texcoords[i * 2 + 1]);
+        let color = |i: usize| {
+            Vec4::new(
+                m.vertex_color[i * 3],
+                m.vertex_color[i * 3 + 1],
+                m.vertex_color[i * 3 + 2],
+                1.0,
+            )
+        };
+
+        for face in m.indices.chunks_exact(3) {
+            let index = [face[0] as usize, face[1] as usize, face[2] as usize];
+            mesh.triangles.push(MeshTriangle {
+                positions: index.map(position),
+                uvs: (!m.texcoords.is_empty()).then(|| index.map(uv)),
+                colors: (!m.vertex_color.is_empty()).then(|| index.map(color)),
+                material: m.material_id,
+            });
+        }
+    }
+
+    Ok(mesh)
+}
+
+fn load_texture(path: &Path, mesh: &mut VoxelMesh) -> Option<usize> {
+    match image::open(path) {
+        Ok(image) => {
+            let image = image.to_rgba8();
+            mesh.textures.push(MeshTexture {
+                width: image.width(),
+                height: image.height(),
+                pixels: image.pixels().map(|p| p.0).collect(),
+            });
+            Some(mesh.textures.len() - 1)
+        }
+        Err(err) => {
+            info!("Error loading texture {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+fn load_gltf(path: &Path) -> io::Result<VoxelMesh> {
+    let (document, buffers, images) =
+        gltf::import(path).map_err(|err| invalid_data(&err.to_string()))?;
+
+    let mut mesh = VoxelMesh::default();
+    for image in images.iter() {
+        let pixels = match image.format {
+            gltf::image::Format::R8G8B8A8 => image
+                .pixels
+                .chunks_exact(4)
+                .map(|p| [p[0], p[1], p[2], p[3]])
+                .collect(),
+            gltf::image::Format::R8G8B8 => image
+                .pixels
+                .chunks_exact(3)
+                .map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            format => {
+                info!(
+                    "Unsupported texture format {:?} in {}",
+                    format,
+                    path.display()
+                );
+                vec![[255; 4]; (image.width * image.height) as usize]
+            }
+        };
+        mesh.textures.push(MeshTexture {
+            width: image.width,
+            height: image.height,
+            pixels,
+        });
+    }
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        mesh.materials.push(MeshMaterial {
+            base_color: Vec4::from_array(pbr.base_color_factor()),
+            texture: pbr
+                .base_color_texture()
+                .map(|info| info.texture().source().index()),
+        });
+    }
+
+    let scene = match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => scene,
+        None => return Ok(mesh),
+    };
+    for node in scene.nodes() {
+        gltf_node(&node, Mat4::IDENTITY, &buffers, &mut mesh);
+    }
+    Ok(mesh)
+}
+
+fn gltf_node(
+    node: &gltf::Node,
+    parent: Mat4,
+    buffers: &[gltf::buffer::Data],
+    mesh: &mut VoxelMesh,
+) {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(gltf_mesh) = node.mesh() {
+        for primitive in gltf_mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: Vec<Vec3> = match reader.read_positions() {
+                Some(positions) => positions
+                    .map(|p| transform.transform_point3(Vec3::from_array(p)))
+                    .collect(),
+                None => continue,
+            };
+            let indices: Vec<usize> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+                None => (0..positions.len()).collect(),
+            };
+            let uvs: Option<Vec<Vec2>> = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().map(Vec2::from_array).collect());
+            let colors: Option<Vec<Vec4>> = reader
+                .read_colors(0)
+                .map(|colors| colors.into_rgba_f32().map(Vec4::from_array).collect());
+            let material = primitive.material().index();
+
+            for face in indices.chunks_exact(3) {
+                let index = [face[0], face[1], face[2]];
+                if index.iter().any(|i| *i >= positions.len()) {
+                    continue;
+                }
+                mesh.triangles.push(MeshTriangle {
+                    positions: index.map(|i| positions[i]),
+                    uvs: uvs.as_ref().map(|uvs| index.map(|i| uvs[i])),
+                    colors: colors.as_ref().map(|colors| index.map(|i| colors[i])),
+                    material,
+                });
+            }
+        }
+    }
+
+    for child in node.children() {
+        gltf_node(&child, transform, buffers, mesh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_cube() -> VoxelMesh {
+        VoxelMesh::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/unit_cube.obj"))
+            .unwrap()
+    }
+
+    /// The cube shrunk to 0.1..0.9 on every axis, so with 0.2 voxels its
+    /// faces lie in the middle of the outer voxels of a 5³ block.
+    fn placement() -> Transform {
+        Transform::from_translation(Vec3::splat(0.5)).with_scale(Vec3::splat(0.8))
+    }
+
+    fn positions(voxels: &[([i32; 3], StorageVoxel)]) -> Vec<[i32; 3]> {
+        let mut positions: Vec<[i32; 3]> = voxels.iter().map(|(pos, _)| *pos).collect();
+        positions.sort();
+        positions
+    }
+
+    fn block(inner: bool) -> Vec<[i32; 3]> {
+        let mut positions = Vec::new();
+        for x in 0..5 {
+            for y in 0..5 {
+                for z in 0..5 {
+                    let edge = [x, y, z].iter().any(|c| *c == 0 || *c == 4);
+                    if edge || inner {
+                        positions.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn surface_mode_gives_a_hollow_shell() {
+        let voxels = voxelize(&unit_cube(), 0.2, placement(), VoxelizeMode::Surface).unwrap();
+        assert_eq!(positions(&voxels), block(false));
+        // the colour comes from the material in the .mtl
+        assert!(voxels.iter().all(|(_, vox)| vox.color == [255, 0, 0]));
+    }
+
+    #[test]
+    fn solid_mode_fills_the_inside() {
+        let voxels = voxelize(&unit_cube(), 0.2, placement(), VoxelizeMode::Solid).unwrap();
+        assert_eq!(positions(&voxels), block(true));
+        assert!(voxels.iter().all(|(_, vox)| vox.color == [255, 0, 0]));
+    }
+
+    #[test]
+    fn empty_textures_use_the_base_color() {
+        let base_color = Vec4::new(0.0, 1.0, 0.0, 1.0);
+        let mesh = VoxelMesh {
+            triangles: Vec::new(),
+            materials: vec![MeshMaterial {
+                base_color,
+                texture: Some(0),
+            }],
+            textures: vec![MeshTexture {
+                width: 0,
+                height: 0,
+                pixels: Vec::new(),
+            }],
+        };
+        let triangle = MeshTriangle {
+            positions: [Vec3::ZERO, Vec3::X, Vec3::Y],
+            uvs: Some([Vec2::ZERO, Vec2::X, Vec2::Y]),
+            colors: None,
+            material: Some(0),
+        };
+        assert_eq!(mesh.color(&triangle, Vec3::splat(1.0 / 3.0)), base_color);
+    }
+}
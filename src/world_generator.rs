@@ -1,102 +1,468 @@
 use core::f32;
 use std::{
-    sync::{Arc, RwLock},
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
     thread,
     time::Instant,
 };
 
-use bevy::{prelude::*, utils::HashMap};
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use dot_vox::{load, Model, Rotation, SceneNode, Voxel};
+use dot_vox::{Dict, DotVoxData, Layer, Model, Rotation, SceneNode, Voxel};
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
 use serde::{Deserialize, Serialize};
 
-use crate::{generate_octree::GenerateOctreeEvent, octree::OctreeVoxel};
+use crate::{
+    binvox::{insert_binvox, load_binvox},
+    chunk::Chunk,
+    generate_octree::GenerateOctreeEvent,
+    heightmap::{load_heightmap, HeightmapSettings},
+    load_error::WorldLoadError,
+    material::{vox_material, MaterialTable, VoxMaterial},
+    octree::OctreeVoxel,
+    qubicle::{insert_qb, load_qb},
+    region::invalid_data,
+    schematic::{load_schematic, BlockTable, BLOCK_TABLE},
+    streaming::{ChunkStreamer, FloatingOrigin},
+    vox_animation::VoxAnimation,
+    vox_asset::{FromVoxScene, VoxScene},
+    voxelizer::{insert_mesh, VoxelizeMode},
+};
 
 pub const VIEWDIST: u32 = 512;
 pub const RENDERDIST: u32 = 512;
 pub const ENTITYDRAW: u32 = 512;
+/// Half the width of the octree, which is centred on the floating origin.
 pub const W_WIDTH: u32 = 4096;
 pub const C_SIZE: u32 = 64;
+/// Voxels must stay within this distance of the origin on every axis, beyond
+/// it f32 positions can no longer tell neighbouring voxels apart.
+pub const WORLD_LIMIT: i32 = 1 << 24;
 
 #[derive(Resource)]
 pub struct VoxWorld {
-    pub world: Arc<RwLock<Vec<Vec<Vec<Chunk>>>>>,
-    pub root: [u32; 3],
+    pub world: Arc<RwLock<ChunkMap>>,
+    pub root: [i32; 3],
+    /// Chunks changed since the last save, by chunk coordinate.
+    pub dirty: Arc<Mutex<HashSet<[i32; 3]>>>,
+    /// Edits not yet sent out as `VoxelsChanged` events.
+    pub changes: Arc<Mutex<Vec<VoxelsChanged>>>,
 }
 impl Default for VoxWorld {
     fn default() -> Self {
         VoxWorld {
-            world: Arc::new(RwLock::new(vec![
-                vec![
-                    vec![
-                        Chunk::default();
-                        ((W_WIDTH * 2) / C_SIZE) as usize
-                    ];
-                    ((W_WIDTH * 2) / C_SIZE) as usize
-                ];
-                ((W_WIDTH * 2) / C_SIZE) as usize
-            ])),
-            root: [W_WIDTH; 3],
+            world: Arc::new(RwLock::new(ChunkMap::default())),
+            root: [0; 3],
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            changes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+/// Sent after the world was edited, with the inclusive bounds of the voxels
+/// that actually changed in world coordinates.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct VoxelsChanged {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+    pub source: EditSource,
+}
+
+/// What an edit came from. Only `User` edits can break pieces off the world,
+/// see `detach_islands`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditSource {
+    /// The edit API, undo and redo, and explosions.
+    User,
+    /// Fluids, falling voxels, animation frames, scene rebuilds and bodies
+    /// merging back into the world.
+    Simulation,
+}
+
+/// One voxel edit, `None` meaning empty space.
+#[derive(Clone, Debug)]
+pub struct VoxelChange {
+    pub pos: [i32; 3],
+    pub before: Option<StorageVoxel>,
+    pub after: Option<StorageVoxel>,
+}
+
+/// Runtime edits, usable from systems and from worker threads alike. Edits
+/// should stay within loaded chunks, as a chunk that is created by an edit
+/// before it streams in replaces whatever was saved there.
+impl VoxWorld {
+    pub fn get_voxel(self: &Self, pos: [i32; 3]) -> Option<StorageVoxel> {
+        self.world.read().unwrap().get_voxel(pos).cloned()
+    }
+
+    /// Places a voxel and returns the one it replaced.
+    pub fn set_voxel(self: &Self, pos: [i32; 3], voxel: StorageVoxel) -> Option<StorageVoxel> {
+        self.apply(vec![(pos, Some(voxel))])
+            .pop()
+            .and_then(|change| change.before)
+    }
+
+    pub fn clear_voxel(self: &Self, pos: [i32; 3]) -> Option<StorageVoxel> {
+        self.apply(vec![(pos, None)])
+            .pop()
+            .and_then(|change| change.before)
+    }
+
+    /// Copies the voxels in the inclusive box `min..=max` out of the loaded
+    /// chunks.
+    pub fn voxels_in(self: &Self, min: [i32; 3], max: [i32; 3]) -> Vec<([i32; 3], StorageVoxel)> {
+        let mut voxels = Vec::new();
+        let world = self.world.read().unwrap();
+        for (chunk_pos, chunk) in world.iter() {
+            for (local, vox) in chunk.iter() {
+                let pos = world_position(chunk_pos, local);
+                if (0..3).all(|i| pos[i] >= min[i] && pos[i] <= max[i]) {
+                    voxels.push((pos, vox.clone()));
+                }
+            }
+        }
+        voxels
+    }
+
+    /// Calls `f` for every position in the inclusive box `min..=max` with the
+    /// voxel currently there, and stores whatever it returns. Return the
+    /// current voxel to leave a position as it is. Only positions that really
+    /// changed are returned and reported.
+    pub fn edit_region(
+        self: &Self,
+        min: [i32; 3],
+        max: [i32; 3],
+        mut f: impl FnMut([i32; 3], Option<&StorageVoxel>) -> Option<StorageVoxel>,
+    ) -> Vec<VoxelChange> {
+        let mut edits = Vec::new();
+        {
+            let world = self.world.read().unwrap();
+            for x in min[0]..=max[0] {
+                for y in min[1]..=max[1] {
+                    for z in min[2]..=max[2] {
+                        let current = world.get_voxel([x, y, z]);
+                        let new = f([x, y, z], current);
+                        if new.as_ref() != current {
+                            edits.push(([x, y, z], new));
+                        }
+                    }
+                }
+            }
+        }
+        self.apply(edits)
+    }
+
+    /// Writes a batch of voxels under one lock, marks their chunks dirty and
+    /// queues a single `VoxelsChanged` covering all of them.
+    pub fn apply(self: &Self, edits: Vec<([i32; 3], Option<StorageVoxel>)>) -> Vec<VoxelChange> {
+        self.apply_from(edits, EditSource::User)
+    }
+
+    /// `apply` for edits that do not come from the user.
+    pub fn apply_from(
+        self: &Self,
+        edits: Vec<([i32; 3], Option<StorageVoxel>)>,
+        source: EditSource,
+    ) -> Vec<VoxelChange> {
+        let mut changes = Vec::with_capacity(edits.len());
+        {
+            let mut world = self.world.write().unwrap();
+            let mut dirty = self.dirty.lock().unwrap();
+            for (pos, voxel) in edits {
+                let before = world.set_voxel(pos, voxel.clone());
+                if before == voxel {
+                    continue;
+                }
+                dirty.insert(chunk_of(pos).0);
+                changes.push(VoxelChange {
+                    pos,
+                    before,
+                    after: voxel,
+                });
+            }
+        }
+
+        if let Some(bounds) = change_bounds(&changes, source) {
+            self.changes.lock().unwrap().push(bounds);
+        }
+        changes
+    }
+}
+
+pub fn change_bounds(changes: &[VoxelChange], source: EditSource) -> Option<VoxelsChanged> {
+    let first = changes.first()?.pos;
+    let mut bounds = VoxelsChanged {
+        min: first,
+        max: first,
+        source,
+    };
+    for change in changes.iter() {
+        for i in 0..3 {
+            bounds.min[i] = bounds.min[i].min(change.pos[i]);
+            bounds.max[i] = bounds.max[i].max(change.pos[i]);
         }
     }
+    Some(bounds)
+}
+
+pub fn send_voxel_changes(world: Res<VoxWorld>, mut event_writer: EventWriter<VoxelsChanged>) {
+    let changes: Vec<VoxelsChanged> = world.changes.lock().unwrap().drain(..).collect();
+    event_writer.send_batch(changes);
 }
 
-#[derive(Default, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Chunk {
-    pub voxels: HashMap<[u16; 3], StorageVoxel>,
+/// Loaded chunks within one voxel of an edit, flow and falling can start on
+/// the other side of a chunk border.
+pub fn chunks_near(change: &VoxelsChanged, chunks: &ChunkMap) -> Vec<[i32; 3]> {
+    let (min, _) = chunk_of(change.min.map(|c| c - 1));
+    let (max, _) = chunk_of(change.max.map(|c| c + 1));
+    chunks
+        .iter()
+        .map(|(pos, _)| pos)
+        .filter(|pos| (0..3).all(|i| pos[i] >= min[i] && pos[i] <= max[i]))
+        .collect()
 }
 
-#[derive(Default, Clone, Serialize, Deserialize, PartialEq)]
+/// The octree is rebuilt around the camera as a whole, so any edit near it
+/// just asks for a new one.
+pub fn rebuild_octree_on_change(
+    mut changes: EventReader<VoxelsChanged>,
+    mut event_writer: EventWriter<GenerateOctreeEvent>,
+) {
+    if changes.read().count() > 0 {
+        event_writer.send(GenerateOctreeEvent);
+    }
+}
+
+/// Sparse chunk storage keyed by chunk coordinate. Only chunks holding at
+/// least one voxel are kept, so empty space costs nothing.
+#[derive(Default, Clone)]
+pub struct ChunkMap {
+    chunks: HashMap<[i32; 3], Chunk>,
+}
+impl ChunkMap {
+    pub fn get(self: &Self, pos: [i32; 3]) -> Option<&Chunk> {
+        self.chunks.get(&pos)
+    }
+
+    pub fn get_mut(self: &mut Self, pos: [i32; 3]) -> Option<&mut Chunk> {
+        self.chunks.get_mut(&pos)
+    }
+
+    /// Returns the chunk at `pos`, creating an empty one if there is none yet.
+    pub fn get_or_insert(self: &mut Self, pos: [i32; 3]) -> &mut Chunk {
+        self.chunks.entry(pos).or_default()
+    }
+
+    /// Replaces the chunk at `pos`, an empty chunk removes it instead.
+    pub fn insert(self: &mut Self, pos: [i32; 3], chunk: Chunk) {
+        if chunk.is_empty() {
+            self.chunks.remove(&pos);
+        } else {
+            self.chunks.insert(pos, chunk);
+        }
+    }
+
+    pub fn remove(self: &mut Self, pos: [i32; 3]) -> Option<Chunk> {
+        self.chunks.remove(&pos)
+    }
+
+    /// Iterates over the chunks that exist, in no particular order.
+    pub fn iter(self: &Self) -> impl Iterator<Item = ([i32; 3], &Chunk)> {
+        self.chunks.iter().map(|(pos, chunk)| (*pos, chunk))
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn get_voxel(self: &Self, pos: [i32; 3]) -> Option<&StorageVoxel> {
+        let (chunk, local) = chunk_of(pos);
+        self.get(chunk)?.get(local)
+    }
+
+    /// Sets or clears a single voxel by world position, creating and dropping
+    /// chunks as needed. Returns what was there before.
+    pub fn set_voxel(
+        self: &mut Self,
+        pos: [i32; 3],
+        voxel: Option<StorageVoxel>,
+    ) -> Option<StorageVoxel> {
+        let (chunk_pos, local) = chunk_of(pos);
+        match voxel {
+            Some(voxel) => self.get_or_insert(chunk_pos).set(local, voxel),
+            None => {
+                let chunk = self.get_mut(chunk_pos)?;
+                let old = chunk.remove(local);
+                if chunk.is_empty() {
+                    self.remove(chunk_pos);
+                }
+                old
+            }
+        }
+    }
+}
+impl IntoIterator for ChunkMap {
+    type Item = ([i32; 3], Chunk);
+    type IntoIter = bevy::utils::hashbrown::hash_map::IntoIter<[i32; 3], Chunk>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.chunks.into_iter()
+    }
+}
+
+/// Linear albedo of a white voxel. Colours are stored as 8-bit sRGB and
+/// multiplied by this once converted to linear, the light constants in
+/// `pathtracer.wgsl` are tuned for it.
+pub const ALBEDO_SCALE: f32 = 0.2;
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct StorageVoxel {
     pub id: u8,
+    /// Albedo as 8-bit sRGB, the same values as the source palette.
     pub color: [u8; 3],
     pub emission: f32,
+    /// Index into the `MaterialTable`.
+    pub material: u16,
 }
 impl StorageVoxel {
+    /// Albedo in linear space, scaled by `ALBEDO_SCALE`.
+    pub fn linear_color(self: &Self) -> Vec3 {
+        let [r, g, b] = self.color;
+        let linear = Color::srgb_u8(r, g, b).to_linear();
+        Vec3::new(linear.red, linear.green, linear.blue) * ALBEDO_SCALE
+    }
+
     pub fn into_normal(self: &Self) -> OctreeVoxel {
         OctreeVoxel {
-            color: Vec3::new(
-                self.color[0] as f32 / 100.0,
-                self.color[1] as f32 / 100.0,
-                self.color[2] as f32 / 100.0,
-            ),
+            color: self.linear_color(),
             emission: self.emission,
             light_color: Vec3::ZERO,
             lit: 0,
             id: self.id as u32,
+            material: self.material as u32,
         }
     }
 }
 
-#[derive(Resource, Clone)]
+/// Splits a world voxel position into its chunk coordinate and the position
+/// local to that chunk.
+pub fn chunk_of(pos: [i32; 3]) -> ([i32; 3], [u8; 3]) {
+    let size = C_SIZE as i32;
+    (
+        [
+            pos[0].div_euclid(size),
+            pos[1].div_euclid(size),
+            pos[2].div_euclid(size),
+        ],
+        [
+            pos[0].rem_euclid(size) as u8,
+            pos[1].rem_euclid(size) as u8,
+            pos[2].rem_euclid(size) as u8,
+        ],
+    )
+}
+
+/// Inverse of `chunk_of`.
+pub fn world_position(chunk_pos: [i32; 3], local: [u8; 3]) -> [i32; 3] {
+    [
+        chunk_pos[0] * C_SIZE as i32 + local[0] as i32,
+        chunk_pos[1] * C_SIZE as i32 + local[1] as i32,
+        chunk_pos[2] * C_SIZE as i32 + local[2] as i32,
+    ]
+}
+
+#[derive(Resource, Clone, Default)]
 pub struct WorldData {
-    pub data: Vec<Vec<Vec<Chunk>>>,
+    pub data: ChunkMap,
+    /// Animated scenes, the world above holds their first frame.
+    pub animations: Vec<VoxAnimation>,
+    /// Named nodes that were split off the scene. Their transforms are in
+    /// world coordinates until `receive_world` makes them local.
+    pub entities: Vec<VoxelEntity>,
+    /// Voxels left out by `VoxImportOptions::clip`.
+    pub clipped: usize,
+    /// The `VoxScene` this was built for, whatever its last build placed is
+    /// replaced.
+    pub scene: Option<Entity>,
+}
+
+/// Options for importing a `.vox` scene, read from a manifest next to it by
+/// `VoxModelLoader`.
+/// `castle.vox` uses `castle.ron`, which looks like
+/// `(include_hidden: false, entities: ["door", "lamp"])`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct VoxImportOptions {
+    /// Also import hidden nodes and nodes on hidden layers.
+    pub include_hidden: bool,
+    /// Names of nodes that are spawned as a `VoxelEntity` instead of being
+    /// baked into the world.
+    pub entities: Vec<String>,
+    /// Leave out voxels that are out of bounds or use a missing palette entry
+    /// instead of failing the import.
+    pub clip: bool,
+    /// Palette entries, counted from 0, whose voxels are granular and fall
+    /// when unsupported.
+    pub granular: Vec<u8>,
 }
-impl Default for WorldData {
+
+/// A model file that is not a `.vox`, placed when the world is built. The
+/// format is picked by extension: `.qb`, `.binvox`, `.schem`, or a mesh
+/// that is voxelized, `.obj`, `.gltf` or `.glb`.
+#[derive(Clone)]
+pub struct ModelImport {
+    pub path: PathBuf,
+    /// World position the model's own origin, or the minimum corner of a
+    /// schematic, is placed at.
+    pub offset: [i32; 3],
+    /// Colour of formats that have none, like `.binvox`.
+    pub color: [u8; 3],
+    /// Spawn the model as entities instead of baking it into the world, one
+    /// for each `.qb` matrix or one for the whole file. Schematics and meshes
+    /// are always baked in.
+    pub as_entity: bool,
+    /// Size of a voxel in mesh units.
+    pub voxel_size: f32,
+    pub mode: VoxelizeMode,
+}
+impl Default for ModelImport {
     fn default() -> Self {
-        WorldData {
-            data: vec![
-                vec![
-                    vec![Chunk::default(); ((W_WIDTH * 2) / C_SIZE) as usize];
-                    ((W_WIDTH * 2) / C_SIZE) as usize
-                ];
-                ((W_WIDTH * 2) / C_SIZE) as usize
-            ],
+        ModelImport {
+            path: PathBuf::new(),
+            offset: [0; 3],
+            color: [200, 200, 200],
+            as_entity: false,
+            voxel_size: 1.0,
+            mode: VoxelizeMode::Solid,
         }
     }
 }
 
+/// Model files that `build_world` loads along with the heightmap.
+#[derive(Resource, Clone, Default)]
+pub struct ModelImports {
+    pub models: Vec<ModelImport>,
+}
+
 #[derive(Component, Clone)]
 pub struct VoxelEntity {
+    pub name: String,
     pub transform: Transform,
-    pub voxels: Vec<Voxel>,
-    pub palette: Vec<dot_vox::Color>,
-    pub materials: Vec<dot_vox::Material>,
+    /// Voxels relative to the transform, Y-up like the world.
+    pub voxels: Vec<([i32; 3], StorageVoxel)>,
 }
 
 #[derive(Resource)]
 pub struct Channel {
-    tx: Sender<WorldData>,
+    pub tx: Sender<WorldData>,
     rx: Receiver<WorldData>,
 }
 
@@ -106,251 +472,744 @@ pub fn setup(mut commands: Commands) {
     commands.insert_resource(VoxWorld::default());
 }
 
-pub fn _spawn_vox_entities(mut commands: Commands, vox_world: Res<VoxWorld>) {
-    let sphere_file = load("Assets/vox_files/sphere.vox").unwrap();
-    commands.spawn((
-        VoxelEntity {
-            transform: Transform::from_xyz(
-                vox_world.root[0] as f32,
-                vox_world.root[1] as f32 + 128.0,
-                vox_world.root[2] as f32 + 512.0,
-            ),
-            voxels: sphere_file.models[0].voxels.clone(),
-            palette: sphere_file.palette,
-            materials: sphere_file.materials,
-        },
-        // MovingEntity,
-    ));
+pub fn _spawn_vox_entities(
+    mut commands: Commands,
+    vox_world: Res<VoxWorld>,
+    asset_server: Res<AssetServer>,
+) {
+    let root = vox_world.root;
+    commands.spawn(VoxScene {
+        entity: Some("sphere".to_string()),
+        ..VoxScene::new(
+            asset_server.load("vox_files/sphere.vox"),
+            Affine3A::from_translation(Vec3::new(
+                root[0] as f32,
+                root[2] as f32 + 512.0,
+                root[1] as f32 + 128.0,
+            )),
+        )
+    });
 }
 
-pub fn build_world(channel: Res<Channel>, vox_world: Res<VoxWorld>) {
+pub fn build_world(
+    mut commands: Commands,
+    channel: Res<Channel>,
+    vox_world: Res<VoxWorld>,
+    streamer: Res<ChunkStreamer>,
+    heightmap: Res<HeightmapSettings>,
+    imports: Res<ModelImports>,
+    materials: Res<MaterialTable>,
+    asset_server: Res<AssetServer>,
+) {
+    // a saved world is streamed in chunk by chunk instead of being rebuilt
+    if streamer.from_save {
+        return;
+    }
+
     let tx = channel.tx.clone();
-    let root = vox_world.root;
+    let heightmap = heightmap.clone();
+    let imports = imports.clone();
+    let materials = materials.clone();
     thread::spawn(move || {
         let now = Instant::now();
 
         let mut world = WorldData::default();
+        if let Err(err) = load_heightmap(&heightmap, &mut world) {
+            info!("Error loading heightmap: {}", err);
+        }
 
-        //spawn 1
-        let vox_data = load("Assets/vox_files/castle.vox").unwrap();
-        let materials = vox_data.materials;
-        let palette = vox_data.palette;
-        process_scene_node(
-            0,
-            &vox_data.scenes,
-            &vox_data.models,
-            Vec3::new(root[0] as f32, root[2] as f32, root[1] as f32),
-            Quat::IDENTITY,
-            &mut world,
-            &palette,
-            &materials,
-        );
-
-        //spawn 2
-        // let vox_data = load("Assets/vox_files/simple_scene.vox").unwrap();
-        // let palette = vox_data.palette;
-        // process_scene_node(
-        //     0,
-        //     &vox_data.scenes,
-        //     &vox_data.models,
-        //     Vec3::new(root[0] as f32, root[2] as f32 + 96.0, root[1] as f32),
-        //     Quat::IDENTITY,
-        //     &mut world,
-        //     &palette,
-        // );
+        for import in imports.models.iter() {
+            match load_model(import, &materials, &mut world) {
+                Ok(placed) => info!("Placed {} voxels from {}", placed, import.path.display()),
+                Err(err) => info!("Error loading {}: {}", import.path.display(), err),
+            }
+        }
 
         let elapsed = now.elapsed().as_millis();
-        info!("World loading took: {}", elapsed);
+        info!("Heightmap and model loading took: {}", elapsed);
 
         match tx.send(world) {
             Ok(_) => {}
             Err(err) => info!("Error sending finished octree: {}", err),
         }
     });
+
+    // scenes are built by `build_vox_scenes` once the asset server has
+    // loaded them
+    let root = vox_world.root;
+
+    //spawn 1
+    commands.spawn(VoxScene::new(
+        asset_server.load("vox_files/castle.vox"),
+        Affine3A::from_translation(Vec3::new(root[0] as f32, root[2] as f32, root[1] as f32)),
+    ));
+
+    //spawn 2
+    // commands.spawn(VoxScene::new(
+    //     asset_server.load("vox_files/simple_scene.vox"),
+    //     Affine3A::from_translation(Vec3::new(
+    //         root[0] as f32,
+    //         root[2] as f32 + 96.0,
+    //         root[1] as f32,
+    //     )),
+    // ));
+}
+
+/// Loads a model file into `world`, by extension. Returns how many voxels
+/// were placed.
+pub fn load_model(
+    import: &ModelImport,
+    materials: &MaterialTable,
+    world: &mut WorldData,
+) -> io::Result<usize> {
+    let name = import
+        .path
+        .file_stem()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let [r, g, b] = import.color;
+    let color = dot_vox::Color { r, g, b, a: 255 };
+
+    match import.path.extension().and_then(|e| e.to_str()) {
+        Some("qb") => {
+            let matrices = load_qb(&import.path)?;
+            let placed = matrices.iter().map(|m| m.voxels.len()).sum();
+            if import.as_entity {
+                world.entities.extend(
+                    matrices
+                        .into_iter()
+                        .map(|matrix| matrix.into_entity(import.offset)),
+                );
+            } else {
+                insert_qb(&matrices, import.offset, world);
+            }
+            Ok(placed)
+        }
+        Some("binvox") => {
+            let binvox = load_binvox(&import.path)?;
+            if import.as_entity {
+                world
+                    .entities
+                    .push(binvox.to_entity(&name, color, import.offset));
+            } else {
+                insert_binvox(&binvox, color, import.offset, world);
+            }
+            Ok(binvox.voxels.len())
+        }
+        Some("schem") => load_schematic(
+            &import.path,
+            &BlockTable::load(BLOCK_TABLE),
+            materials,
+            import.offset,
+            world,
+        ),
+        Some("obj") | Some("gltf") | Some("glb") => insert_mesh(
+            &import.path,
+            import.voxel_size,
+            // in mesh units, so the offset stays in voxels
+            Transform::from_translation(
+                IVec3::from_array(import.offset).as_vec3() * import.voxel_size,
+            ),
+            import.mode,
+            world,
+        ),
+        _ => Err(invalid_data(&format!(
+            "{} is not a model file that can be imported",
+            import.path.display()
+        ))),
+    }
+}
+
+/// Builds a loaded `.vox` scene into a world of its own, along with its
+/// animation and entities. With `entity` set the whole scene becomes one
+/// `VoxelEntity` of that name, showing only its first frame since animations
+/// play on world voxels. `name` is only used in messages.
+pub fn build_vox_scene(
+    name: &str,
+    vox_data: &DotVoxData,
+    options: &VoxImportOptions,
+    transform: Affine3A,
+    entity: Option<&str>,
+    materials: &MaterialTable,
+) -> Result<WorldData, WorldLoadError> {
+    let now = Instant::now();
+    let palette = vox_palette(vox_data, &options.granular, materials);
+
+    let mut world = WorldData::default();
+    process_scene_node(0, vox_data, &palette, transform, 0, options, &mut world)?;
+    if entity.is_none() {
+        if let Some(animation) = VoxAnimation::from_scene(vox_data, &palette, transform, options)? {
+            world.animations.push(animation);
+        }
+    }
+
+    let names = scene_node_names(&vox_data.scenes);
+    for node_name in options.entities.iter() {
+        if !names.contains_key(node_name) {
+            info!("{} has no node named {}", name, node_name);
+        }
+    }
+
+    if let Some(entity_name) = entity {
+        let clipped = world.clipped;
+        let mut entities = std::mem::take(&mut world.entities);
+        entities.push(entity_from_world(entity_name, transform, world));
+        world = WorldData {
+            entities,
+            clipped,
+            ..Default::default()
+        };
+    }
+
+    let elapsed = now.elapsed().as_millis();
+    info!("Building {} took: {}", name, elapsed);
+    Ok(world)
 }
 
+/// Makes sure every node, child and model the scene graph refers to exists,
+/// that nodes have at least one frame or model, and that there are no
+/// cycles. `process_scene_node` relies on this.
+pub fn check_scene(path: &str, vox_data: &DotVoxData) -> Result<(), WorldLoadError> {
+    let scenes = &vox_data.scenes;
+    if scenes.is_empty() {
+        return Err(WorldLoadError::bad_file(path, "file has no scene graph"));
+    }
+    let node = |index: u32| {
+        if (index as usize) < scenes.len() {
+            Ok(index)
+        } else {
+            Err(WorldLoadError::bad_file(
+                path,
+                format!("scene node {} does not exist", index),
+            ))
+        }
+    };
+
+    // depth first from the root, a node already on the path is a cycle
+    let mut on_path = vec![false; scenes.len()];
+    let mut stack: Vec<(u32, bool)> = vec![(0, true)];
+    while let Some((index, entering)) = stack.pop() {
+        if !entering {
+            on_path[index as usize] = false;
+            continue;
+        }
+        if on_path[index as usize] {
+            return Err(WorldLoadError::bad_file(
+                path,
+                format!("scene node {} is its own ancestor", index),
+            ));
+        }
+        on_path[index as usize] = true;
+        stack.push((index, false));
+
+        match &scenes[index as usize] {
+            SceneNode::Transform { frames, child, .. } => {
+                if frames.is_empty() {
+                    return Err(WorldLoadError::bad_file(
+                        path,
+                        format!("transform node {} has no frames", index),
+                    ));
+                }
+                stack.push((node(*child)?, true));
+            }
+            SceneNode::Group { children, .. } => {
+                for child in children.iter() {
+                    stack.push((node(*child)?, true));
+                }
+            }
+            SceneNode::Shape { models, .. } => {
+                if models.is_empty() {
+                    return Err(WorldLoadError::bad_file(
+                        path,
+                        format!("shape node {} has no models", index),
+                    ));
+                }
+                for model in models.iter() {
+                    if model.model_id as usize >= vox_data.models.len() {
+                        return Err(WorldLoadError::bad_file(
+                            path,
+                            format!("model {} does not exist", model.model_id),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Walks the MagicaVoxel scene graph. `transform` is the accumulated affine
+/// transform of all parent nodes, in MagicaVoxel's Z-up space. `palette` is
+/// the file's palette from `vox_palette`, and `vox_data` must have passed
+/// `check_scene`.
 pub fn process_scene_node(
     node: u32,
-    scenes: &Vec<SceneNode>,
-    r_models: &Vec<Model>,
-    root: Vec3,
-    rot: Quat,
+    vox_data: &DotVoxData,
+    palette: &[StorageVoxel],
+    transform: Affine3A,
+    frame: u32,
+    options: &VoxImportOptions,
     world: &mut WorldData,
-    palette: &Vec<dot_vox::Color>,
-    materials: &Vec<dot_vox::Material>,
-) {
-    match &scenes[node as usize] {
-        SceneNode::Transform { frames, child, .. } => {
-            if frames.len() != 1 {
-                unimplemented!("Multiple frame in transform node");
+) -> Result<(), WorldLoadError> {
+    let scene_node = &vox_data.scenes[node as usize];
+    if !options.include_hidden && is_hidden(scene_node, &vox_data.layers) {
+        return Ok(());
+    }
+
+    match scene_node {
+        SceneNode::Transform {
+            attributes,
+            frames,
+            child,
+            ..
+        } => {
+            let keyframe = keyframe_at(frames, frame, |f| &f.attributes);
+            let transform = transform * frame_transform(keyframe);
+
+            // named nodes from the manifest become entities of their own
+            if let Some(name) = attributes.get("_name") {
+                if options.entities.contains(name) {
+                    let mut entity_world = WorldData::default();
+                    process_scene_node(
+                        *child,
+                        vox_data,
+                        palette,
+                        transform,
+                        frame,
+                        options,
+                        &mut entity_world,
+                    )?;
+                    world.clipped += entity_world.clipped;
+                    world
+                        .entities
+                        .push(entity_from_world(name, transform, entity_world));
+                    return Ok(());
+                }
             }
 
-            let frame = &frames[0];
-
-            let this_translation = frame
-                .position()
-                .map(|position| Vec3 {
-                    x: position.x as f32,
-                    y: position.y as f32,
-                    z: position.z as f32,
-                })
-                .unwrap_or(Vec3::ZERO);
-            let this_rotation = frame
-                .orientation()
-                .unwrap_or(Rotation::IDENTITY)
-                .to_quat_scale()
-                .0;
-
-            let rotation = Quat::from_vec4(Vec4::new(
-                this_rotation[0],
-                this_rotation[1],
-                this_rotation[2],
-                this_rotation[3],
-            ));
-            let translation = root + this_translation;
-
-            process_scene_node(
-                *child,
-                scenes,
-                r_models,
-                translation,
-                rotation,
-                world,
-                palette,
-                materials,
-            );
+            process_scene_node(*child, vox_data, palette, transform, frame, options, world)
         }
         SceneNode::Group { children, .. } => {
             // Process each child node recursively
             for child_index in children {
                 process_scene_node(
                     *child_index,
-                    scenes,
-                    r_models,
-                    root,
-                    rot,
-                    world,
+                    vox_data,
                     palette,
-                    materials,
-                );
+                    transform,
+                    frame,
+                    options,
+                    world,
+                )?;
             }
+            Ok(())
         }
         SceneNode::Shape { models, .. } => {
             // Insert voxels using the calculated current position
+            let model = keyframe_at(models, frame, |m| &m.attributes);
             insert_voxels(
-                &r_models[models[0].model_id as usize],
-                root,
-                rot,
+                &vox_data.models[model.model_id as usize],
+                transform,
                 palette,
-                materials,
+                options.clip,
                 world,
-            );
+            )
         }
     }
 }
 
-fn insert_voxels(
-    model: &Model,
-    root: Vec3,
-    rotation: Quat,
-    palette: &Vec<dot_vox::Color>,
-    materials: &Vec<dot_vox::Material>,
-    world: &mut WorldData,
-) {
-    for vox in model.voxels.iter() {
-        let mut voxel_position = Vec3::new(
-            (root[0] as f32 - (model.size.x as f32 / 2.0)) + vox.x as f32,
-            (root[1] as f32 - (model.size.y as f32 / 2.0)) + vox.y as f32,
-            (root[2] as f32 - (model.size.z as f32 / 2.0)) + vox.z as f32,
-        );
-
-        let rotation = rotation.to_euler(EulerRot::XZY);
-        voxel_position = rotate_around_x(voxel_position, root, rotation.0);
-        voxel_position = rotate_around_z(voxel_position, root, rotation.1);
-        voxel_position = rotate_around_y(voxel_position, root, rotation.2);
-
-        voxel_position = Vec3::new(voxel_position.x, voxel_position.z, voxel_position.y);
+/// A node is hidden by its own `_hidden` flag or, for transform nodes, by
+/// the flag of the layer it is on.
+fn is_hidden(node: &SceneNode, layers: &[Layer]) -> bool {
+    let hidden = |attributes: &Dict| attributes.get("_hidden").map_or(false, |h| h == "1");
+    match node {
+        SceneNode::Transform {
+            attributes,
+            layer_id,
+            ..
+        } => {
+            hidden(attributes)
+                || layers
+                    .get(*layer_id as usize)
+                    .map_or(false, |layer| hidden(&layer.attributes))
+        }
+        SceneNode::Group { attributes, .. } | SceneNode::Shape { attributes, .. } => {
+            hidden(attributes)
+        }
+    }
+}
 
-        let (xx, yy, zz) = (
-            (voxel_position.x as u32 / C_SIZE),
-            (voxel_position.y as u32 / C_SIZE),
-            (voxel_position.z as u32 / C_SIZE),
-        );
+/// Named nodes of a scene, by name. Names are set on transform nodes.
+pub fn scene_node_names(scenes: &[SceneNode]) -> HashMap<String, u32> {
+    let mut names = HashMap::new();
+    for (index, node) in scenes.iter().enumerate() {
+        if let SceneNode::Transform { attributes, .. } = node {
+            if let Some(name) = attributes.get("_name") {
+                names.insert(name.clone(), index as u32);
+            }
+        }
+    }
+    names
+}
 
-        let vox_color = palette[vox.i as usize];
-        let color = get_u8_color(vox_color);
-        let emission = materials[vox.i as usize].emission().unwrap_or(0.0);
-        let id = id_from_color([vox_color.r, vox_color.g, vox_color.b]);
+/// Turns the voxels of a named node into an entity placed at the node's
+/// pivot, with the voxels relative to it. The pivot is a world position, the
+/// floating origin may move before the entity is spawned.
+fn entity_from_world(name: &str, transform: Affine3A, world: WorldData) -> VoxelEntity {
+    let pivot = transform.translation;
+    let pivot = [
+        pivot.x.floor() as i32,
+        pivot.z.floor() as i32,
+        pivot.y.floor() as i32,
+    ];
 
-        let chunk = &mut world.data[xx as usize][yy as usize][zz as usize];
+    let mut voxels = Vec::new();
+    for (chunk_pos, chunk) in world.data.iter() {
+        for (local, vox) in chunk.iter() {
+            voxels.push((
+                [
+                    chunk_pos[0] * C_SIZE as i32 + local[0] as i32 - pivot[0],
+                    chunk_pos[1] * C_SIZE as i32 + local[1] as i32 - pivot[1],
+                    chunk_pos[2] * C_SIZE as i32 + local[2] as i32 - pivot[2],
+                ],
+                vox.clone(),
+            ));
+        }
+    }
 
-        chunk.voxels.insert(
-            [
-                voxel_position.x as u16,
-                voxel_position.y as u16,
-                voxel_position.z as u16,
-            ],
-            StorageVoxel {
-                id,
-                color,
-                emission,
-            },
-        );
+    VoxelEntity {
+        name: name.to_string(),
+        transform: Transform::from_xyz(pivot[0] as f32, pivot[1] as f32, pivot[2] as f32),
+        voxels,
     }
 }
 
-fn rotate_around_x(p: Vec3, center: Vec3, angle: f32) -> Vec3 {
-    let ty = p.y - center.y;
-    let tz = p.z - center.z;
-
-    let cos_theta = angle.cos();
-    let sin_theta = angle.sin();
+/// MagicaVoxel keyframes hold until the next one, so this picks the last
+/// keyframe at or before `frame`, falling back to the first one.
+pub fn keyframe_at<'a, T>(
+    keyframes: &'a [T],
+    frame: u32,
+    attributes: impl Fn(&T) -> &Dict,
+) -> &'a T {
+    keyframes
+        .iter()
+        .filter(|k| frame_index(attributes(k)) <= frame)
+        .max_by_key(|k| frame_index(attributes(k)))
+        .unwrap_or(&keyframes[0])
+}
 
-    let rotated_y = ty * cos_theta - tz * sin_theta;
-    let rotated_z = ty * sin_theta + tz * cos_theta;
+/// The `_f` attribute of a transform frame or shape model, 0 when missing.
+pub fn frame_index(attributes: &Dict) -> u32 {
+    attributes
+        .get("_f")
+        .and_then(|f| f.parse().ok())
+        .unwrap_or(0)
+}
 
-    return Vec3::new(p.x, rotated_y + center.y, rotated_z + center.z);
+/// Number of animation frames in a scene, 1 for a still scene.
+pub fn scene_frame_count(scenes: &[SceneNode]) -> u32 {
+    let mut last = 0;
+    for node in scenes.iter() {
+        match node {
+            SceneNode::Transform { frames, .. } => {
+                for frame in frames.iter() {
+                    last = last.max(frame_index(&frame.attributes));
+                }
+            }
+            SceneNode::Shape { models, .. } => {
+                for model in models.iter() {
+                    last = last.max(frame_index(&model.attributes));
+                }
+            }
+            SceneNode::Group { .. } => {}
+        }
+    }
+    last + 1
 }
 
-fn rotate_around_y(p: Vec3, center: Vec3, angle: f32) -> Vec3 {
-    let tx = p.x - center.x;
-    let tz = p.z - center.z;
+/// Translation, rotation and the mirror flips of one transform node frame.
+pub fn frame_transform(frame: &dot_vox::Frame) -> Affine3A {
+    let translation = frame
+        .position()
+        .map(|position| Vec3::new(position.x as f32, position.y as f32, position.z as f32))
+        .unwrap_or(Vec3::ZERO);
+    let (rotation, scale) = frame
+        .orientation()
+        .unwrap_or(Rotation::IDENTITY)
+        .to_quat_scale();
 
-    let cos_theta = angle.cos();
-    let sin_theta = angle.sin();
+    Affine3A::from_scale_rotation_translation(
+        Vec3::from_array(scale),
+        Quat::from_array(rotation),
+        translation,
+    )
+}
 
-    let rotated_x = tx * cos_theta + tz * sin_theta;
-    let rotated_z = -tx * sin_theta + tz * cos_theta;
+/// Position of a model voxel in the world. Models are centred on their
+/// transform, so the voxel centre is placed relative to the middle of the
+/// model and floored afterwards, which stays exact under rotations and
+/// mirroring. MagicaVoxel is Z-up, the world is Y-up.
+pub fn model_voxel_position(model: &Model, vox: &Voxel, transform: Affine3A) -> [i32; 3] {
+    let half = Vec3::new(
+        model.size.x as f32,
+        model.size.y as f32,
+        model.size.z as f32,
+    ) / 2.0;
+    let center = Vec3::new(vox.x as f32 + 0.5, vox.y as f32 + 0.5, vox.z as f32 + 0.5) - half;
+    let p = transform.transform_point3(center);
 
-    return Vec3::new(rotated_x + center.x, p.y, rotated_z + center.z);
+    [p.x.floor() as i32, p.z.floor() as i32, p.y.floor() as i32]
 }
 
-fn rotate_around_z(p: Vec3, center: Vec3, angle: f32) -> Vec3 {
-    let tx = p.x - center.x;
-    let ty = p.y - center.y;
+/// Places the voxels of a model. With `clip` set, voxels that can't be
+/// placed are counted in `world.clipped` instead of failing.
+fn insert_voxels(
+    model: &Model,
+    transform: Affine3A,
+    palette: &[StorageVoxel],
+    clip: bool,
+    world: &mut WorldData,
+) -> Result<(), WorldLoadError> {
+    for vox in model.voxels.iter() {
+        let position = model_voxel_position(model, vox, transform);
+        let inside_model = (vox.x as u32) < model.size.x
+            && (vox.y as u32) < model.size.y
+            && (vox.z as u32) < model.size.z;
+        let error = if !inside_model || position.iter().any(|c| c.abs() >= WORLD_LIMIT) {
+            Some(WorldLoadError::OutOfBounds { position })
+        } else if vox.i as usize >= palette.len() {
+            Some(WorldLoadError::BadPaletteIndex {
+                index: vox.i,
+                palette_len: palette.len(),
+            })
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            if !clip {
+                return Err(error);
+            }
+            world.clipped += 1;
+            continue;
+        }
+
+        let (chunk_pos, local) = chunk_of(position);
+        let chunk = world.data.get_or_insert(chunk_pos);
+        chunk.set(local, palette[vox.i as usize].clone());
+    }
+    Ok(())
+}
 
-    let cos_theta = angle.cos();
-    let sin_theta = angle.sin();
+/// The stored form of every palette entry of a `.vox` file, with its `MATL`
+/// material added to the table. Entries listed in `granular` get a granular
+/// copy of their material.
+pub fn vox_palette(
+    vox_data: &DotVoxData,
+    granular: &[u8],
+    materials: &MaterialTable,
+) -> Vec<StorageVoxel> {
+    let mut material_ids = materials.register_vox(&vox_data.materials);
+    for i in granular.iter() {
+        let id = &mut material_ids[*i as usize];
+        *id = materials.index_of(VoxMaterial {
+            granular: true,
+            ..materials.get(*id)
+        });
+    }
+    vox_data
+        .palette
+        .iter()
+        .take(256)
+        .enumerate()
+        .map(|(i, color)| {
+            let emission = vox_material(&vox_data.materials, i)
+                .and_then(|m| m.emission())
+                .unwrap_or(0.0);
+            StorageVoxel {
+                material: material_ids[i],
+                ..color_voxel(*color, emission)
+            }
+        })
+        .collect()
+}
 
-    let rotated_x = tx * cos_theta - ty * sin_theta;
-    let rotated_y = tx * sin_theta + ty * cos_theta;
+/// The stored form of a colour from any importer, so every format ends up
+/// with the same ids and colour range. The voxel uses the default material.
+pub fn color_voxel(color: dot_vox::Color, emission: f32) -> StorageVoxel {
+    StorageVoxel {
+        id: id_from_color([color.r, color.g, color.b]),
+        color: [color.r, color.g, color.b],
+        emission,
+        material: 0,
+    }
+}
 
-    return Vec3::new(rotated_x + center.x, rotated_y + center.y, p.z);
+/// Places voxels given relative to `offset` into the world.
+pub fn insert_voxel_list(
+    voxels: &[([i32; 3], StorageVoxel)],
+    offset: [i32; 3],
+    world: &mut WorldData,
+) {
+    for (pos, vox) in voxels.iter() {
+        world.data.set_voxel(
+            [pos[0] + offset[0], pos[1] + offset[1], pos[2] + offset[2]],
+            Some(vox.clone()),
+        );
+    }
 }
 
 pub fn receive_world(
+    mut commands: Commands,
     channel: Res<Channel>,
     world: Res<VoxWorld>,
+    origin: Res<FloatingOrigin>,
+    mut streamer: ResMut<ChunkStreamer>,
+    mut scenes: Query<&mut VoxScene>,
+    spawned: Query<(Entity, &FromVoxScene)>,
     mut event_writer: EventWriter<GenerateOctreeEvent>,
 ) {
     for _ in 0..channel.rx.len() {
         if let Ok(result) = channel.rx.try_recv() {
-            *world.world.write().unwrap() = result.data;
+            // a rebuilt scene first takes out what its last build put in
+            if let Some(scene) = result.scene {
+                let mut vox_scene = match scenes.get_mut(scene) {
+                    Ok(vox_scene) => vox_scene,
+                    // despawned while it was being built
+                    Err(_) => continue,
+                };
+                world.apply_from(
+                    vox_scene.placed.drain(..).map(|pos| (pos, None)).collect(),
+                    EditSource::Simulation,
+                );
+                for (entity, from) in spawned.iter() {
+                    if from.0 == scene {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+
+            let mut placed = Vec::new();
+            {
+                let mut chunks = world.world.write().unwrap();
+                let mut dirty = world.dirty.lock().unwrap();
+                for (pos, chunk) in result.data {
+                    // everything that was generated still has to reach disk once
+                    dirty.insert(pos);
+                    streamer.mark_loaded(pos);
+                    if result.scene.is_some() {
+                        placed.extend(chunk.iter().map(|(local, _)| world_position(pos, local)));
+                    }
+                    // merge, other scenes or the heightmap may share the chunk
+                    match chunks.get_mut(pos) {
+                        Some(existing) => {
+                            for (local, vox) in chunk.iter() {
+                                existing.set(local, vox.clone());
+                            }
+                        }
+                        None => chunks.insert(pos, chunk),
+                    }
+                }
+            }
+
+            let entities = result.entities.into_iter().map(|mut entity| {
+                let pivot = entity.transform.translation.round().as_ivec3().to_array();
+                entity.transform.translation = origin.to_local(pivot);
+                entity
+            });
+            match result.scene {
+                Some(scene) => {
+                    // later frames put voxels where the first one has none
+                    for animation in result.animations.iter() {
+                        placed.extend(animation.positions());
+                    }
+                    placed.sort();
+                    placed.dedup();
+                    if let Ok(mut vox_scene) = scenes.get_mut(scene) {
+                        vox_scene.placed = placed;
+                    }
+                    for animation in result.animations {
+                        commands.spawn((animation, FromVoxScene(scene)));
+                    }
+                    for entity in entities {
+                        commands.spawn((entity, FromVoxScene(scene)));
+                    }
+                }
+                None => {
+                    for animation in result.animations {
+                        commands.spawn(animation);
+                    }
+                    for entity in entities {
+                        commands.spawn(entity);
+                    }
+                }
+            }
             event_writer.send(GenerateOctreeEvent);
         }
     }
 }
 
+/// Procedural terrain for chunks that are neither part of the loaded scene
+/// nor saved to disk.
+#[derive(Resource, Clone)]
+pub struct TerrainSettings {
+    pub enabled: bool,
+    pub seed: u32,
+    /// World height the terrain surface varies around.
+    pub height: i32,
+    pub amplitude: f32,
+    pub frequency: f64,
+    pub color: [u8; 3],
+}
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seed: 0,
+            height: -128,
+            amplitude: 48.0,
+            frequency: 0.004,
+            color: [24, 105, 20],
+        }
+    }
+}
+
+pub fn generate_chunk(pos: [i32; 3], settings: &TerrainSettings) -> Chunk {
+    let mut chunk = Chunk::default();
+    if !settings.enabled {
+        return chunk;
+    }
+
+    let size = C_SIZE as i32;
+    let bottom = pos[1] * size;
+    if bottom > settings.height + settings.amplitude as i32 {
+        return chunk;
+    }
+
+    let noise = Fbm::<Perlin>::new(settings.seed).set_octaves(4);
+    let color = dot_vox::Color {
+        r: settings.color[0],
+        g: settings.color[1],
+        b: settings.color[2],
+        a: 255,
+    };
+    let voxel = color_voxel(color, 0.0);
+
+    for x in 0..size {
+        for z in 0..size {
+            let wx = (pos[0] * size + x) as f64;
+            let wz = (pos[2] * size + z) as f64;
+            let surface = settings.height
+                + (noise.get([wx * settings.frequency, wz * settings.frequency])
+                    * settings.amplitude as f64) as i32;
+            for y in 0..size.min(surface - bottom + 1) {
+                chunk.set([x as u8, y as u8, z as u8], voxel.clone());
+            }
+        }
+    }
+
+    chunk
+}
+
 pub fn _get_color_by_id(id: u8) -> [u8; 3] {
     let (r, g, b) = match id {
         1 => (50, 50, 50),
@@ -361,7 +1220,7 @@ pub fn _get_color_by_id(id: u8) -> [u8; 3] {
         _ => (10, 10, 10),
     };
 
-    return get_u8_color(dot_vox::Color { r, g, b, a: 255 });
+    return [r, g, b];
 }
 
 pub fn id_from_color(color: [u8; 3]) -> u8 {
@@ -378,14 +1237,161 @@ pub fn id_from_color(color: [u8; 3]) -> u8 {
     }
 }
 
-pub fn map_range(from_range: (f32, f32), to_range: (f32, f32), s: f32) -> f32 {
-    to_range.0 + (s - from_range.0) * (to_range.1 - to_range.0) / (from_range.1 - from_range.0)
+/// The palette colour of a stored voxel.
+pub fn get_vox_color(color: [u8; 3]) -> dot_vox::Color {
+    dot_vox::Color {
+        r: color[0],
+        g: color[1],
+        b: color[2],
+        a: 255,
+    }
 }
 
-pub fn get_u8_color(color: dot_vox::Color) -> [u8; 3] {
-    let r = map_range((0.0, 255.0), (0.0, 0.2), color.r as f32);
-    let g = map_range((0.0, 255.0), (0.0, 0.2), color.g as f32);
-    let b = map_range((0.0, 255.0), (0.0, 0.2), color.b as f32);
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    /// A 2×1×1 model of a red and a blue voxel, placed three times: below a
+    /// translated transform, a nested group and a translated, rotated
+    /// transform, below a hidden node, and below a node on a hidden layer.
+    fn scene_graph() -> DotVoxData {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/scene_graph.vox");
+        dot_vox::load_bytes(&fs::read(path).unwrap()).unwrap()
+    }
+
+    fn placed_colors(world: &WorldData) -> Vec<([i32; 3], [u8; 3])> {
+        let mut voxels = Vec::new();
+        for (pos, chunk) in world.data.iter() {
+            for (local, vox) in chunk.iter() {
+                voxels.push((world_position(pos, local), vox.color));
+            }
+        }
+        voxels.sort();
+        voxels
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn nested_and_rotated_transforms_compose() {
+        let vox_data = scene_graph();
+        check_scene("scene_graph.vox", &vox_data).unwrap();
+        let world = build_vox_scene(
+            "scene_graph.vox",
+            &vox_data,
+            &VoxImportOptions::default(),
+            Affine3A::IDENTITY,
+            None,
+            &MaterialTable::default(),
+        )
+        .unwrap();
+
+        // translated by (10, 20, 0) and turned a quarter around z, so the
+        // model's x axis points along MagicaVoxel's y, which is the world's z
+        assert_eq!(
+            placed_colors(&world),
+            vec![([10, 0, 19], RED), ([10, 0, 20], BLUE)]
+        );
+    }
+
+    #[test]
+    fn hidden_nodes_and_layers_are_skipped_unless_included() {
+        let vox_data = scene_graph();
+        let options = VoxImportOptions {
+            include_hidden: true,
+            ..Default::default()
+        };
+        let world = build_vox_scene(
+            "scene_graph.vox",
+            &vox_data,
+            &options,
+            Affine3A::IDENTITY,
+            None,
+            &MaterialTable::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            placed_colors(&world),
+            vec![
+                ([-1, 0, 0], RED),
+                ([-1, 50, 0], RED),
+                ([0, 0, 0], BLUE),
+                ([0, 50, 0], BLUE),
+                ([10, 0, 19], RED),
+                ([10, 0, 20], BLUE),
+            ]
+        );
+    }
+
+    #[test]
+    fn scene_offset_applies_after_the_nodes() {
+        let vox_data = scene_graph();
+        let world = build_vox_scene(
+            "scene_graph.vox",
+            &vox_data,
+            &VoxImportOptions::default(),
+            Affine3A::from_translation(Vec3::new(100.0, -64.0, 7.0)),
+            None,
+            &MaterialTable::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            placed_colors(&world),
+            vec![([110, 7, -45], RED), ([110, 7, -44], BLUE)]
+        );
+    }
+
+    #[test]
+    fn model_files_load_by_extension() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let materials = MaterialTable::default();
+        let mut world = WorldData::default();
+
+        let binvox = ModelImport {
+            path: fixtures.join("model.binvox"),
+            offset: [0, 10, 0],
+            color: [1, 2, 3],
+            ..Default::default()
+        };
+        assert_eq!(load_model(&binvox, &materials, &mut world).unwrap(), 3);
+        assert_eq!(
+            world.data.get_voxel([0, 10, 0]).map(|v| v.color),
+            Some([1, 2, 3])
+        );
+
+        let qb = ModelImport {
+            path: fixtures.join("model.qb"),
+            as_entity: true,
+            ..Default::default()
+        };
+        assert_eq!(load_model(&qb, &materials, &mut world).unwrap(), 2);
+        assert_eq!(world.entities.len(), 1);
+        assert_eq!(world.entities[0].name, "body");
+
+        let material_lib = ModelImport {
+            path: fixtures.join("unit_cube.mtl"),
+            ..Default::default()
+        };
+        assert!(load_model(&material_lib, &materials, &mut world).is_err());
+    }
 
-    return [(r * 100.0) as u8, (g * 100.0) as u8, (b * 100.0) as u8];
+    #[test]
+    fn cycles_and_missing_nodes_are_rejected() {
+        let mut vox_data = scene_graph();
+        if let SceneNode::Group { children, .. } = &mut vox_data.scenes[3] {
+            children.push(1);
+        }
+        assert!(check_scene("cycle.vox", &vox_data).is_err());
+
+        let mut vox_data = scene_graph();
+        if let SceneNode::Group { children, .. } = &mut vox_data.scenes[1] {
+            children.push(42);
+        }
+        assert!(check_scene("missing.vox", &vox_data).is_err());
+    }
 }